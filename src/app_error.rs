@@ -65,6 +65,18 @@ pub enum ServerError {
     UnexpectedError { message: String },
     #[error("MessagePack Decode Error")]
     MessagePackDecodeError(#[from] rmp_serde::decode::Error),
+    #[error("Missing or invalid authorization token")]
+    Unauthorized,
+    #[error("Not allowed to access {0}")]
+    Forbidden(String),
+    #[error("Database operation failed")]
+    Database(#[from] rocksdb::Error),
+    #[error("Invalid value in database for key {0}")]
+    InvalidDatabaseValue(String),
+    #[error("Too many requests, retry after {retry_after} seconds")]
+    TooManyRequests { retry_after: u64 },
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
 }
 
 impl From<ServerError> for std::io::Error {
@@ -5,31 +5,57 @@ use std::future::Future;
 use std::{
     pin::Pin,
     sync::{Arc, Weak},
-    time::{Duration, Instant},
+    time::Duration,
 };
 use tokio::sync::broadcast;
+use tokio::time::Instant;
 use tracing::info;
 
 use crate::app_error::SendableError;
 
 pub type BoxFut<'a, O> = Pin<Box<dyn Future<Output = O> + Send + 'a>>;
 
+#[derive(Clone, Copy, Debug)]
+pub struct CachePolicy {
+    // Values older than this get refreshed in the background
+    pub refresh_interval: Duration,
+    // Every value waits a random part of this longer before refreshing, so values fetched together don't all refresh at once
+    pub refresh_jitter: Duration,
+    // Values older than this aren't returned while refreshing, requests wait on the refresh instead
+    pub max_stale: Duration,
+    // Return the last value, no matter how old, when refreshing fails
+    pub stale_if_error: bool,
+    // After a failed fetch, no new fetch is started until this has passed
+    pub retry_delay: Duration,
+}
+
+impl CachePolicy {
+    fn get_refresh_at(&self, fetched_at: Instant) -> Instant {
+        fetched_at + self.refresh_interval + self.refresh_jitter.mul_f64(rand::random::<f64>())
+    }
+}
+
 #[derive(Clone)]
 pub struct Cached<T>
 where
     T: Clone + Send + Sync + 'static,
 {
     inner: Arc<Mutex<CachedInner<T>>>,
-    refresh_interval: Duration,
+    policy: CachePolicy,
 }
 
-type LastFetched<T> = Option<(Instant, T)>;
+struct CachedValue<T> {
+    fetched_at: Instant,
+    refresh_at: Instant,
+    value: T,
+}
 
 struct CachedInner<T>
 where
     T: Clone + Send + Sync + 'static,
 {
-    last_fetched: LastFetched<T>,
+    last_fetched: Option<CachedValue<T>>,
+    last_failure: Option<(Instant, SendableError)>,
     inflight: Option<Weak<broadcast::Sender<Result<T, SendableError>>>>,
 }
 
@@ -40,6 +66,7 @@ where
     fn default() -> Self {
         Self {
             last_fetched: None,
+            last_failure: None,
             inflight: None,
         }
     }
@@ -49,37 +76,59 @@ impl<T> Cached<T>
 where
     T: Clone + Send + Sync + 'static,
 {
-    pub fn new(refresh_interval: Duration) -> Self {
+    pub fn new(policy: CachePolicy) -> Self {
         Self {
             inner: Default::default(),
-            refresh_interval,
+            policy,
         }
     }
 
+    /// The last fetched value, even if it's stale, without fetching
+    pub fn get_value(&self) -> Option<T> {
+        self.inner
+            .lock()
+            .last_fetched
+            .as_ref()
+            .map(|fetched| fetched.value.clone())
+    }
+
     pub async fn get_cached<F, E>(&self, f: F) -> Result<T, SendableError>
     where
         F: FnOnce(Option<T>) -> BoxFut<'static, Result<T, E>> + Send + 'static,
         E: std::fmt::Display + 'static,
     {
-        let mut rx = {
+        let (mut rx, fallback) = {
             // only sync code in this block
             let mut inner = self.inner.lock();
+            let now = Instant::now();
 
-            if let Some((fetched_at, value)) = inner.last_fetched.as_ref() {
-                if fetched_at.elapsed() < self.refresh_interval {
-                    return Ok(value.clone());
-                } else {
-                    info!("stale, let's refresh");
+            // stale data that can be returned right away, and stale data that can be returned if refreshing fails
+            let mut stale = None;
+            let mut fallback = None;
+            if let Some(fetched) = inner.last_fetched.as_ref() {
+                if now < fetched.refresh_at {
+                    return Ok(fetched.value.clone());
+                }
+                info!("stale, let's refresh");
+                if now.duration_since(fetched.fetched_at) < self.policy.max_stale {
+                    stale = Some(fetched.value.clone());
+                }
+                if self.policy.stale_if_error {
+                    fallback = Some(fetched.value.clone());
                 }
             }
 
-            let last_fetched = inner.last_fetched.clone().map(|v| v.1);
-            if let Some(inflight) = inner.inflight.as_ref().and_then(Weak::upgrade) {
-                if let Some(val) = last_fetched {
-                    info!("Returning stale data");
-                    return Ok(val);
+            if let Some((failed_at, err)) = inner.last_failure.as_ref() {
+                if now.duration_since(*failed_at) < self.policy.retry_delay {
+                    info!("failed recently, not fetching again yet");
+                    return match stale.or(fallback) {
+                        Some(val) => Ok(val),
+                        None => Err(err.clone()),
+                    };
                 }
+            }
 
+            let rx = if let Some(inflight) = inner.inflight.as_ref().and_then(Weak::upgrade) {
                 inflight.subscribe()
             } else {
                 // there isn't, let's fetch
@@ -88,45 +137,165 @@ where
                 let tx = Arc::new(tx);
                 // and only store a weak reference in our state:
                 inner.inflight = Some(Arc::downgrade(&tx));
-                let inner = self.inner.clone();
+                let inner_ref = self.inner.clone();
+                let policy = self.policy;
 
                 // call the closure first, so we don't send _it_ across threads,
                 // just the Future it returns
-                let fut = f(last_fetched.clone());
+                let fut = f(inner
+                    .last_fetched
+                    .as_ref()
+                    .map(|fetched| fetched.value.clone()));
 
                 tokio::spawn(async move {
                     let res = fut.await;
 
                     {
                         // only sync code in this block
-                        let mut inner = inner.lock();
+                        let mut inner = inner_ref.lock();
                         inner.inflight = None;
 
                         match res {
                             Ok(value) => {
-                                inner.last_fetched.replace((Instant::now(), value.clone()));
+                                let fetched_at = Instant::now();
+                                inner.last_fetched = Some(CachedValue {
+                                    fetched_at,
+                                    refresh_at: policy.get_refresh_at(fetched_at),
+                                    value: value.clone(),
+                                });
+                                inner.last_failure = None;
                                 let _ = tx.send(Ok(value));
                             }
                             Err(e) => {
-                                let _ = tx.send(Err(SendableError {
-                                    inner: e.to_string(),
-                                }));
+                                let err = SendableError::new(e);
+                                inner.last_failure = Some((Instant::now(), err.clone()));
+                                let _ = tx.send(Err(err));
                             }
                         };
                     }
                 });
 
-                if let Some(val) = last_fetched {
-                    info!("Returning stale data");
-                    return Ok(val);
-                }
-
                 rx
+            };
+
+            if let Some(val) = stale {
+                info!("Returning stale data");
+                return Ok(val);
             }
+
+            (rx, fallback)
         };
 
         // if we reached here, we're waiting for an in-flight request (we weren't able to serve from cache)
-        let received = rx.recv().await??;
-        Ok(received)
+        match rx.recv().await? {
+            Ok(received) => Ok(received),
+            Err(err) => match fallback {
+                Some(val) => {
+                    info!("Refresh failed, returning stale data");
+                    Ok(val)
+                }
+                None => Err(err),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures_util::future::join_all;
+    use tokio::time::{advance, sleep};
+
+    use super::*;
+
+    fn get_policy(refresh_interval: Duration, max_stale: Duration) -> CachePolicy {
+        CachePolicy {
+            refresh_interval,
+            refresh_jitter: Duration::ZERO,
+            max_stale,
+            stale_if_error: false,
+            retry_delay: Duration::ZERO,
+        }
+    }
+
+    // Returns the attempt number, attempts after `fail_after` fail
+    fn fetch(
+        attempts: &Arc<AtomicUsize>,
+        fail_after: usize,
+    ) -> impl FnOnce(Option<usize>) -> BoxFut<'static, Result<usize, String>> {
+        let attempts = attempts.clone();
+        move |_last_val| {
+            Box::pin(async move {
+                sleep(Duration::from_millis(20)).await;
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst) + 1;
+                if attempt > fail_after {
+                    Err(String::from("fetch failed"))
+                } else {
+                    Ok(attempt)
+                }
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn coalesces_fetches() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let cached = Cached::new(get_policy(Duration::from_secs(60), Duration::MAX));
+
+        let results = join_all((0..10).map(|_| cached.get_cached(fetch(&attempts, 10)))).await;
+        assert!(results.into_iter().all(|res| res.unwrap() == 1));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        assert_eq!(cached.get_cached(fetch(&attempts, 10)).await.unwrap(), 1);
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_while_revalidate() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let cached = Cached::new(get_policy(Duration::ZERO, Duration::MAX));
+
+        assert_eq!(cached.get_cached(fetch(&attempts, 10)).await.unwrap(), 1);
+        // stale data is returned right away, while it's refreshed in the background
+        assert_eq!(cached.get_cached(fetch(&attempts, 10)).await.unwrap(), 1);
+        assert_eq!(cached.get_value(), Some(1));
+        // time is paused, so the clock only moves on once the refresh has finished
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(cached.get_value(), Some(2));
+
+        // too stale to return, so it waits on the refresh
+        let cached = Cached::new(get_policy(Duration::ZERO, Duration::ZERO));
+        assert_eq!(cached.get_cached(fetch(&attempts, 10)).await.unwrap(), 3);
+        assert_eq!(cached.get_cached(fetch(&attempts, 10)).await.unwrap(), 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_if_error_and_retry_delay() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let policy = CachePolicy {
+            stale_if_error: true,
+            retry_delay: Duration::from_secs(60),
+            ..get_policy(Duration::ZERO, Duration::ZERO)
+        };
+        let cached = Cached::new(policy);
+
+        assert_eq!(cached.get_cached(fetch(&attempts, 1)).await.unwrap(), 1);
+        assert_eq!(cached.get_cached(fetch(&attempts, 1)).await.unwrap(), 1);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        // the refresh failed, so it isn't retried until the retry delay passed
+        assert_eq!(cached.get_cached(fetch(&attempts, 1)).await.unwrap(), 1);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        advance(Duration::from_secs(60)).await;
+        assert_eq!(cached.get_cached(fetch(&attempts, 1)).await.unwrap(), 1);
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        let cached: Cached<usize> = Cached::new(CachePolicy {
+            stale_if_error: false,
+            ..policy
+        });
+        assert!(cached.get_cached(fetch(&attempts, 0)).await.is_err());
+        assert!(cached.get_cached(fetch(&attempts, 0)).await.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
     }
 }
@@ -0,0 +1,342 @@
+use std::env;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::app_error::{AppResult, ServerError};
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TarballCacheConfig {
+    // Bytes the unpacked tarballs can take up
+    pub max_size: u64,
+    // Seconds a tarball stays cached without being requested
+    pub time_to_idle: u64,
+    // Seconds before a cached tarball gets downloaded again
+    pub refresh_interval: u64,
+    // Up to this many seconds get added to the refresh interval of every tarball, to spread out refreshes
+    pub refresh_jitter: u64,
+    // Seconds a tarball can be served while it's downloaded again, older tarballs wait on the download
+    pub max_stale: u64,
+    // Serve the cached tarball, no matter how old, when downloading it again fails
+    pub stale_if_error: bool,
+    // Seconds before a failed download is attempted again
+    pub retry_delay: u64,
+    // Bytes a tarball counts for while it's downloading, before its unpacked size is known
+    pub downloading_weight: u32,
+}
+
+impl Default for TarballCacheConfig {
+    fn default() -> Self {
+        TarballCacheConfig {
+            max_size: 512 * 1024 * 1024,
+            time_to_idle: 86400,
+            refresh_interval: 604800,
+            refresh_jitter: 86400,
+            max_stale: 30 * 86400,
+            stale_if_error: true,
+            retry_delay: 5,
+            downloading_weight: 1_000_000,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PackageCacheConfig {
+    // The capacity is sized between these by the request stats
+    pub min_capacity: u64,
+    pub max_capacity: u64,
+    // Seconds before a package that's missing a version gets refetched from npm
+    pub missing_package_refetch_interval: u64,
+    // Packages that were found to not exist, or to miss a version, are remembered for missing_ttl seconds
+    pub missing_capacity: usize,
+    pub missing_ttl: u64,
+}
+
+impl Default for PackageCacheConfig {
+    fn default() -> Self {
+        PackageCacheConfig {
+            min_capacity: 500,
+            max_capacity: 2000,
+            missing_package_refetch_interval: 60,
+            missing_capacity: 10000,
+            missing_ttl: 300,
+        }
+    }
+}
+
+/// Seconds responses can be cached by browsers and the CDN
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheTtlConfig {
+    // Files of a package version, these never change
+    pub files: u32,
+    // Resolved dependencies, these change whenever a new version gets published
+    pub resolutions: u32,
+    pub metadata: u32,
+    pub errors: u32,
+    pub sync_status: u32,
+}
+
+impl Default for CacheTtlConfig {
+    fn default() -> Self {
+        CacheTtlConfig {
+            files: 365 * 24 * 3600,
+            resolutions: 3600,
+            metadata: 300,
+            errors: 300,
+            sync_status: 300,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    // Threads reading from and writing to the db at the same time
+    pub blocking_threads: usize,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            blocking_threads: 64,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub tarball_cache: TarballCacheConfig,
+    pub package_cache: PackageCacheConfig,
+    pub cache_ttl: CacheTtlConfig,
+    pub database: DatabaseConfig,
+}
+
+fn override_value<T: FromStr>(
+    value: &mut T,
+    name: &str,
+    get_env: &impl Fn(&str) -> Option<String>,
+) -> AppResult<()> {
+    if let Some(env_value) = get_env(name) {
+        *value = env_value
+            .parse()
+            .map_err(|_| ServerError::InvalidConfig(format!("{} has an invalid value", name)))?;
+    }
+    Ok(())
+}
+
+fn check_capacity(name: &str, min_capacity: u64, max_capacity: u64) -> AppResult<()> {
+    if min_capacity == 0 || min_capacity > max_capacity {
+        return Err(ServerError::InvalidConfig(format!(
+            "{} capacity should be between 1 and max_capacity, got {} and {}",
+            name, min_capacity, max_capacity
+        )));
+    }
+    Ok(())
+}
+
+impl Config {
+    /// Reads the TOML file at CONFIG_PATH if it's set, then applies the env overrides
+    pub fn load() -> AppResult<Config> {
+        let mut config = match env::var("CONFIG_PATH") {
+            Ok(path) => {
+                let content = std::fs::read_to_string(&path).map_err(|err| {
+                    ServerError::InvalidConfig(format!("Could not read {}: {}", path, err))
+                })?;
+                Config::parse(&content)?
+            }
+            Err(_) => Config::default(),
+        };
+        config.apply_env_overrides(|name| env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn parse(content: &str) -> AppResult<Config> {
+        toml::from_str(content).map_err(|err| ServerError::InvalidConfig(err.to_string()))
+    }
+
+    fn apply_env_overrides(&mut self, get_env: impl Fn(&str) -> Option<String>) -> AppResult<()> {
+        let tarball_cache = &mut self.tarball_cache;
+        override_value(
+            &mut tarball_cache.max_size,
+            "TARBALL_CACHE_MAX_SIZE",
+            &get_env,
+        )?;
+        override_value(
+            &mut tarball_cache.time_to_idle,
+            "TARBALL_CACHE_TIME_TO_IDLE",
+            &get_env,
+        )?;
+        override_value(
+            &mut tarball_cache.refresh_interval,
+            "TARBALL_CACHE_REFRESH_INTERVAL",
+            &get_env,
+        )?;
+        override_value(
+            &mut tarball_cache.refresh_jitter,
+            "TARBALL_CACHE_REFRESH_JITTER",
+            &get_env,
+        )?;
+        override_value(
+            &mut tarball_cache.max_stale,
+            "TARBALL_CACHE_MAX_STALE",
+            &get_env,
+        )?;
+        override_value(
+            &mut tarball_cache.stale_if_error,
+            "TARBALL_CACHE_STALE_IF_ERROR",
+            &get_env,
+        )?;
+        override_value(
+            &mut tarball_cache.retry_delay,
+            "TARBALL_CACHE_RETRY_DELAY",
+            &get_env,
+        )?;
+        override_value(
+            &mut tarball_cache.downloading_weight,
+            "TARBALL_CACHE_DOWNLOADING_WEIGHT",
+            &get_env,
+        )?;
+
+        let package_cache = &mut self.package_cache;
+        override_value(
+            &mut package_cache.min_capacity,
+            "PACKAGE_CACHE_MIN_CAPACITY",
+            &get_env,
+        )?;
+        override_value(
+            &mut package_cache.max_capacity,
+            "PACKAGE_CACHE_MAX_CAPACITY",
+            &get_env,
+        )?;
+        override_value(
+            &mut package_cache.missing_package_refetch_interval,
+            "PACKAGE_CACHE_MISSING_PACKAGE_REFETCH_INTERVAL",
+            &get_env,
+        )?;
+        override_value(
+            &mut package_cache.missing_capacity,
+            "PACKAGE_CACHE_MISSING_CAPACITY",
+            &get_env,
+        )?;
+        override_value(
+            &mut package_cache.missing_ttl,
+            "PACKAGE_CACHE_MISSING_TTL",
+            &get_env,
+        )?;
+
+        let cache_ttl = &mut self.cache_ttl;
+        override_value(&mut cache_ttl.files, "CACHE_TTL_FILES", &get_env)?;
+        override_value(
+            &mut cache_ttl.resolutions,
+            "CACHE_TTL_RESOLUTIONS",
+            &get_env,
+        )?;
+        override_value(&mut cache_ttl.metadata, "CACHE_TTL_METADATA", &get_env)?;
+        override_value(&mut cache_ttl.errors, "CACHE_TTL_ERRORS", &get_env)?;
+        override_value(
+            &mut cache_ttl.sync_status,
+            "CACHE_TTL_SYNC_STATUS",
+            &get_env,
+        )?;
+
+        override_value(
+            &mut self.database.blocking_threads,
+            "DATABASE_BLOCKING_THREADS",
+            &get_env,
+        )?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> AppResult<()> {
+        check_capacity(
+            "package_cache",
+            self.package_cache.min_capacity,
+            self.package_cache.max_capacity,
+        )?;
+        if self.tarball_cache.max_size == 0
+            || self.tarball_cache.time_to_idle == 0
+            || self.tarball_cache.refresh_interval == 0
+        {
+            return Err(ServerError::InvalidConfig(String::from(
+                "tarball_cache max_size, time_to_idle and refresh_interval should be more than 0",
+            )));
+        }
+        if self.tarball_cache.max_stale < self.tarball_cache.refresh_interval {
+            return Err(ServerError::InvalidConfig(String::from(
+                "tarball_cache max_stale should be at least refresh_interval",
+            )));
+        }
+        if self.package_cache.missing_capacity == 0 || self.database.blocking_threads == 0 {
+            return Err(ServerError::InvalidConfig(String::from(
+                "package_cache missing_capacity and database blocking_threads should be more than 0",
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config = Config::parse(
+            r#"
+            [tarball_cache]
+            max_size = 1000000
+
+            [cache_ttl]
+            errors = 60
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.tarball_cache.max_size, 1000000);
+        assert_eq!(config.tarball_cache.time_to_idle, 86400);
+        assert_eq!(config.cache_ttl.errors, 60);
+        assert_eq!(config.package_cache, PackageCacheConfig::default());
+
+        assert!(Config::parse("[tarball_cache]\nmax_capacity = 1000").is_err());
+    }
+
+    #[test]
+    fn env_overrides_and_validation() {
+        let mut config = Config::default();
+        config
+            .apply_env_overrides(|name| match name {
+                "PACKAGE_CACHE_MAX_CAPACITY" => Some(String::from("5000")),
+                "CACHE_TTL_RESOLUTIONS" => Some(String::from("600")),
+                "TARBALL_CACHE_STALE_IF_ERROR" => Some(String::from("false")),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(config.package_cache.max_capacity, 5000);
+        assert!(!config.tarball_cache.stale_if_error);
+        assert_eq!(config.cache_ttl.resolutions, 600);
+        assert!(config.validate().is_ok());
+
+        assert!(config
+            .apply_env_overrides(|name| match name {
+                "CACHE_TTL_FILES" => Some(String::from("forever")),
+                _ => None,
+            })
+            .is_err());
+
+        config.package_cache.min_capacity = 6000;
+        assert!(config.validate().is_err());
+        config.package_cache.min_capacity = 500;
+        config.tarball_cache.max_size = 0;
+        assert!(config.validate().is_err());
+        config.tarball_cache.max_size = 1000;
+        config.tarball_cache.max_stale = 60;
+        assert!(config.validate().is_err());
+        config.tarball_cache.max_stale = 2592000;
+        config.database.blocking_threads = 0;
+        assert!(config.validate().is_err());
+    }
+}
@@ -1,4 +1,9 @@
-use crate::npm_replicator::{registry::NpmRocksDB, replication_task};
+use crate::config::Config;
+use crate::npm::package_content::PackageContentFetcher;
+use crate::npm::prefetcher::{spawn_prefetcher, PrefetchConfig};
+use crate::npm::registries::RegistryConfig;
+use crate::npm::request_stats::RequestStats;
+use crate::npm_replicator::{hooks::ReplicationHooks, registry::NpmRocksDB, replication_task};
 use dotenv::dotenv;
 use std::env;
 use std::net::SocketAddr;
@@ -7,6 +12,7 @@ use warp::Filter;
 
 mod app_error;
 mod cached;
+mod config;
 mod npm;
 mod npm_replicator;
 mod package;
@@ -26,12 +32,44 @@ async fn main() -> Result<(), std::io::Error> {
     .parse::<u16>()
     .unwrap();
 
+    let config = Config::load()?;
+
     // Setup npm db
     let npm_registry_path =
         env::var("NPM_ROCKS_DB").expect("NPM_ROCKS_DB env variable should be set");
-    let npm_fs_db = NpmRocksDB::new(&npm_registry_path);
+    let npm_fs_db = NpmRocksDB::new(
+        &npm_registry_path,
+        RegistryConfig::from_env(),
+        config.package_cache,
+        config.database,
+    )?
+    .with_hooks(ReplicationHooks::from_env());
+
+    // Size the package cache to fit the packages that make up most of the traffic
+    let request_stats = RequestStats::load(&npm_fs_db)?;
+    let working_set_size = request_stats.get_working_set_size(0.9) as u64;
+    let npm_fs_db = npm_fs_db.with_cache_capacity(working_set_size);
+    request_stats.spawn_persist_worker(npm_fs_db.clone());
+    let popular_packages: Vec<String> = request_stats
+        .get_top(500)
+        .into_iter()
+        .map(|pkg| pkg.name)
+        .collect();
+    let warm_db = npm_fs_db.clone();
+    tokio::task::spawn_blocking(move || warm_db.warm_cache(&popular_packages));
 
-    replication_task::spawn_sync_thread(npm_fs_db.clone());
+    let replication_supervisor = replication_task::spawn_sync_thread(npm_fs_db.clone())?;
+
+    let pkg_content_fetcher =
+        PackageContentFetcher::new(npm_fs_db.registries.clone(), &config.tarball_cache);
+    pkg_content_fetcher.register_metrics();
+    spawn_prefetcher(
+        npm_fs_db.clone(),
+        pkg_content_fetcher.clone(),
+        request_stats.clone(),
+        npm_fs_db.hooks(),
+        PrefetchConfig::from_env()?,
+    );
 
     // cors headers
     let mut headers = HeaderMap::new();
@@ -46,10 +84,15 @@ async fn main() -> Result<(), std::io::Error> {
     );
     let cors_headers_filter = warp::reply::with::headers(headers);
 
-    let filter = router::routes::routes(npm_fs_db)
-        .with(warp::trace::request())
-        .with(cors_headers_filter)
-        .with(warp::compression::gzip());
+    let filter = router::routes::routes(
+        npm_fs_db,
+        pkg_content_fetcher,
+        request_stats,
+        replication_supervisor,
+        config,
+    )?
+    .with(warp::trace::request())
+    .with(cors_headers_filter);
 
     let addr: SocketAddr = ([0, 0, 0, 0], port).into();
     println!("Server running on {}", addr);
@@ -1,16 +1,22 @@
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
     fmt,
+    hash::{Hash, Hasher},
+    sync::Arc,
 };
 
 use node_semver::{Range, Version};
+use serde::{Deserialize, Deserializer, Serialize};
 use tracing::{error, info};
 
 use crate::{
-    app_error::ServerError, npm_replicator::registry::NpmRocksDB,
+    app_error::ServerError,
+    npm_replicator::{registry::NpmRocksDB, types::document::MinimalPackageData},
     package::process::parse_package_specifier_no_validation,
 };
 
+use super::platform::{get_incompatibility, TargetPlatform};
+
 #[derive(Clone, Eq, Hash, PartialEq, Debug)]
 pub enum DepRange {
     Range(Range),
@@ -43,15 +49,36 @@ impl fmt::Display for DepRange {
     }
 }
 
-#[derive(Clone, Eq, Hash, PartialEq, Debug)]
+#[derive(Clone, Debug)]
 pub struct DepRequest {
     name: String,
     range: DepRange,
+    optional: bool,
+}
+
+// Requests are the same dependency regardless of whether they're optional, see `insert_dep_request`
+impl PartialEq for DepRequest {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.range == other.range
+    }
+}
+
+impl Eq for DepRequest {}
+
+impl Hash for DepRequest {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        self.range.hash(state);
+    }
 }
 
 impl DepRequest {
     fn new(name: String, range: DepRange) -> DepRequest {
-        DepRequest { name, range }
+        DepRequest {
+            name,
+            range,
+            optional: false,
+        }
     }
 
     pub fn from_name_version(name: String, version: String) -> Result<DepRequest, ServerError> {
@@ -69,28 +96,179 @@ impl DepRequest {
         }
         Ok(DepRequest::new(name, parsed_range))
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// A dependency is only optional if every package requesting it marks it as optional
+fn insert_dep_request(deps: &mut HashSet<DepRequest>, request: DepRequest) {
+    if !request.optional || !deps.contains(&request) {
+        deps.replace(request);
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ResolverOptions {
+    // Let ranges match prerelease versions, like npm's `--include-prerelease`
+    pub include_prerelease: bool,
+    // Resolve to the `latest` dist-tag whenever it satisfies the range, like npm does
+    pub prefer_latest: bool,
+    // Skip deprecated versions, unless every matching version is deprecated
+    pub exclude_deprecated: bool,
+    // Prefer versions that support this platform and skip optional dependencies that don't
+    pub platform: Option<TargetPlatform>,
+    // Prefer versions whose `engines.node` range allows this node version
+    #[serde(deserialize_with = "deserialize_node_version")]
+    pub node_version: Option<Version>,
+}
+
+fn deserialize_node_version<'de, D>(deserializer: D) -> Result<Option<Version>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value: Option<String> = Option::deserialize(deserializer)?;
+    match value {
+        Some(value) => Version::parse(value)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+impl ResolverOptions {
+    fn get_incompatibility(&self, data: &MinimalPackageData, version: &str) -> Option<String> {
+        let version_data = data.versions.get(version)?;
+        get_incompatibility(
+            version_data,
+            self.platform.as_ref(),
+            self.node_version.as_ref(),
+        )
+    }
+}
+
+fn satisfies(range: &Range, version: &Version, options: &ResolverOptions) -> bool {
+    if range.satisfies(version) {
+        return true;
+    }
+
+    if options.include_prerelease && version.is_prerelease() {
+        // Range::satisfies only lets prereleases through when a comparator has the same major.minor.patch,
+        // allows_any compares against the bounds by precedence alone, so 1.10.0-beta.1 stays below ^1.10.0
+        return Range::parse(version.to_string())
+            .map(|exact| range.allows_any(&exact))
+            .unwrap_or(false);
+    }
+
+    false
+}
+
+/// Finds the version a range resolves to, comparing versions by semver precedence
+/// as the versions map is sorted as strings (1.10.0 comes before 1.9.0).
+/// Versions that are incompatible with the target only get picked when nothing else matches,
+/// versions that aren't valid semver are skipped.
+pub fn find_matching_version(
+    data: &MinimalPackageData,
+    range: &Range,
+    options: &ResolverOptions,
+) -> Result<Option<Version>, ServerError> {
+    let is_deprecated = |version: &Version| -> bool {
+        data.versions
+            .get(&version.to_string())
+            .map(|version_data| version_data.deprecated.is_some())
+            .unwrap_or(false)
+    };
+    let is_compatible = |version: &Version| -> bool {
+        options
+            .get_incompatibility(data, &version.to_string())
+            .is_none()
+    };
+    let is_preferred = |version: &Version| -> bool {
+        is_compatible(version) && !(options.exclude_deprecated && is_deprecated(version))
+    };
+
+    if options.prefer_latest {
+        // An invalid latest tag is treated as not having one
+        if let Some(Ok(latest_version)) = data.dist_tags.get("latest").map(Version::parse) {
+            if satisfies(range, &latest_version, options) && is_preferred(&latest_version) {
+                return Ok(Some(latest_version));
+            }
+        }
+    }
+
+    let mut candidates: Vec<Version> = Vec::new();
+    for version in data.versions.keys() {
+        let parsed_version = match Version::parse(version) {
+            Ok(parsed_version) => parsed_version,
+            Err(_err) => continue,
+        };
+        if satisfies(range, &parsed_version, options) {
+            candidates.push(parsed_version);
+        }
+    }
+
+    let highest_preferred = candidates.iter().filter(|v| is_preferred(v)).max();
+    if let Some(version) = highest_preferred {
+        return Ok(Some(version.clone()));
+    }
+
+    let highest_compatible = candidates.iter().filter(|v| is_compatible(v)).max();
+    if let Some(version) = highest_compatible {
+        return Ok(Some(version.clone()));
+    }
+
+    Ok(candidates.into_iter().max())
 }
 
 pub type ResolutionsMap = BTreeMap<String, Version>;
 pub type AliasesMap = BTreeMap<String, String>;
 
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum WarningKind {
+    Deprecated,
+    Incompatible,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ResolutionWarning {
+    pub name: String,
+    pub version: String,
+    pub kind: WarningKind,
+    pub message: String,
+}
+
+// Called with every package name before it's looked up, resolving fails with the error it returns
+pub type AccessCheck = Arc<dyn Fn(&str) -> Result<(), ServerError> + Send + Sync>;
+
 pub struct DepTreeBuilder {
     pub resolutions: ResolutionsMap,
     pub aliases: AliasesMap,
     packages: HashMap<String, HashSet<Version>>,
     npm_db: NpmRocksDB,
+    options: ResolverOptions,
+    access_check: Option<AccessCheck>,
 }
 
 impl DepTreeBuilder {
-    pub fn new(npm_db: NpmRocksDB) -> DepTreeBuilder {
+    pub fn new(npm_db: NpmRocksDB, options: ResolverOptions) -> DepTreeBuilder {
         DepTreeBuilder {
             resolutions: BTreeMap::new(),
             aliases: BTreeMap::new(),
             packages: HashMap::new(),
             npm_db,
+            options,
+            access_check: None,
         }
     }
 
+    pub fn with_access_check(mut self, access_check: AccessCheck) -> Self {
+        self.access_check = Some(access_check);
+        self
+    }
+
     fn add_dependency(&mut self, name: &str, version: &Version) {
         let mut key = String::from(name);
         key.push('@');
@@ -116,7 +294,7 @@ impl DepTreeBuilder {
     fn has_dependency(&mut self, name: &str, range: &Range) -> bool {
         if let Some(versions) = self.packages.get(&String::from(name)) {
             for version in versions {
-                if range.satisfies(version) {
+                if satisfies(range, version, &self.options) {
                     // TODO: Make this only run for dev builds, it slows down requests a lot sometimes...
                     // println!(
                     //     "{}@{} is already resolved, skipping",
@@ -136,6 +314,9 @@ impl DepTreeBuilder {
         request: DepRequest,
         mut transient_deps: HashSet<DepRequest>,
     ) -> Result<HashSet<DepRequest>, ServerError> {
+        if let Some(access_check) = &self.access_check {
+            access_check(&request.name)?;
+        }
         let data = self.npm_db.get_package(&request.name)?;
         let mut range = Range::any();
         if let DepRange::Tag(tag) = &request.range {
@@ -167,23 +348,31 @@ impl DepTreeBuilder {
             return Ok(transient_deps);
         }
 
-        let mut highest_version: Option<Version> = None;
-        for (version, _data) in data.versions.iter().rev() {
-            let parsed_version = Version::parse(version)?;
-            if range.satisfies(&parsed_version) {
-                highest_version = Some(parsed_version);
-                break;
+        if let Some(resolved_version) = find_matching_version(&data, &range, &self.options)? {
+            if request.optional {
+                let incompatibility = self
+                    .options
+                    .get_incompatibility(&data, &resolved_version.to_string());
+                if let Some(reason) = incompatibility {
+                    info!("Skipping optional dependency: {}", reason);
+                    return Ok(transient_deps);
+                }
             }
-        }
 
-        if let Some(resolved_version) = highest_version {
             self.add_dependency(&request.name, &resolved_version);
 
             let data = data.versions.get(&resolved_version.to_string());
             if let Some(data) = data {
-                for (name, range) in data.dependencies.iter() {
-                    transient_deps
-                        .insert(DepRequest::from_name_version(name.clone(), range.clone())?);
+                // npm also lists optional dependencies under dependencies, but not every registry does
+                for (name, range) in data
+                    .dependencies
+                    .iter()
+                    .chain(data.optional_dependencies.iter())
+                {
+                    let mut dep_request =
+                        DepRequest::from_name_version(name.clone(), range.clone())?;
+                    dep_request.optional = data.optional_dependencies.contains_key(name);
+                    insert_dep_request(&mut transient_deps, dep_request);
                 }
             }
             Ok(transient_deps)
@@ -226,4 +415,219 @@ impl DepTreeBuilder {
 
         Ok(())
     }
+
+    /// Lists the warnings `npm install` would show for the resolved packages
+    #[tracing::instrument(name = "collect_warnings", skip_all)]
+    pub fn collect_warnings(&self) -> Result<Vec<ResolutionWarning>, ServerError> {
+        let mut warnings: Vec<ResolutionWarning> = Vec::new();
+        for (key, version) in self.resolutions.iter() {
+            let name = match key.rsplit_once('@') {
+                Some((name, _major)) => name,
+                None => continue,
+            };
+            let data = self.npm_db.get_package(name)?;
+            let version_str = version.to_string();
+            if let Some(version_data) = data.versions.get(&version_str) {
+                if let Some(message) = &version_data.deprecated {
+                    warnings.push(ResolutionWarning {
+                        name: String::from(name),
+                        version: version_str.clone(),
+                        kind: WarningKind::Deprecated,
+                        message: message.clone(),
+                    });
+                }
+            }
+            if let Some(reason) = self.options.get_incompatibility(&data, &version_str) {
+                warnings.push(ResolutionWarning {
+                    name: String::from(name),
+                    version: version_str,
+                    kind: WarningKind::Incompatible,
+                    message: reason,
+                });
+            }
+        }
+        Ok(warnings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::npm_replicator::types::document::MinimalPackageVersionData;
+    use crate::utils::test_utils::create_test_db;
+
+    fn create_package(versions: &[(&str, Option<&str>)], latest: &str) -> MinimalPackageData {
+        let mut data = MinimalPackageData {
+            name: String::from("foo"),
+            ..Default::default()
+        };
+        data.dist_tags
+            .insert(String::from("latest"), String::from(latest));
+        for (version, deprecated) in versions {
+            data.versions.insert(
+                version.to_string(),
+                MinimalPackageVersionData {
+                    tarball: format!("https://registry.npmjs.org/foo/-/foo-{}.tgz", version),
+                    deprecated: deprecated.map(String::from),
+                    ..Default::default()
+                },
+            );
+        }
+        data
+    }
+
+    fn resolve(data: &MinimalPackageData, range: &str, options: &ResolverOptions) -> String {
+        let range = Range::parse(range).unwrap();
+        find_matching_version(data, &range, options)
+            .unwrap()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn semver_precedence() {
+        let data = create_package(&[("1.9.0", None), ("1.10.0", None)], "1.10.0");
+        assert_eq!(resolve(&data, "^1.0.0", &Default::default()), "1.10.0");
+    }
+
+    #[test]
+    fn skip_invalid_versions() {
+        let data = create_package(&[("1.9.0", None), ("not-a-version", None)], "latest");
+        assert_eq!(resolve(&data, "^1.0.0", &Default::default()), "1.9.0");
+        let options = ResolverOptions {
+            prefer_latest: true,
+            ..Default::default()
+        };
+        assert_eq!(resolve(&data, "^1.0.0", &options), "1.9.0");
+    }
+
+    #[test]
+    fn prefer_latest() {
+        let data = create_package(&[("1.9.0", None), ("1.10.0", None)], "1.9.0");
+        let options = ResolverOptions {
+            prefer_latest: true,
+            ..Default::default()
+        };
+        assert_eq!(resolve(&data, "^1.0.0", &options), "1.9.0");
+        assert_eq!(resolve(&data, "^1.10.0", &options), "1.10.0");
+    }
+
+    #[test]
+    fn exclude_deprecated() {
+        let data = create_package(
+            &[("1.9.0", None), ("1.10.0", Some("Critical bug, use 1.9.0"))],
+            "1.10.0",
+        );
+        let options = ResolverOptions {
+            exclude_deprecated: true,
+            ..Default::default()
+        };
+        assert_eq!(resolve(&data, "^1.0.0", &options), "1.9.0");
+        assert_eq!(resolve(&data, "^1.10.0", &options), "1.10.0");
+    }
+
+    #[test]
+    fn include_prerelease() {
+        let data = create_package(&[("1.9.0", None), ("1.10.0-beta.1", None)], "1.9.0");
+        let options = ResolverOptions {
+            include_prerelease: true,
+            ..Default::default()
+        };
+        assert_eq!(resolve(&data, "^1.0.0", &options), "1.10.0-beta.1");
+
+        // Prereleases of the release a range starts at come before it, so they don't satisfy it
+        let data = create_package(
+            &[("1.10.0-beta.1", None), ("2.0.0-rc.1", None)],
+            "1.10.0-beta.1",
+        );
+        let find = |range: &str| {
+            find_matching_version(&data, &Range::parse(range).unwrap(), &options).unwrap()
+        };
+        assert_eq!(find("^1.10.0"), None);
+        assert_eq!(find("^2.0.0"), None);
+        assert_eq!(find(">=1.10.0").unwrap().to_string(), "2.0.0-rc.1");
+        assert_eq!(find("^1.9.0").unwrap().to_string(), "1.10.0-beta.1");
+    }
+
+    #[test]
+    fn prefer_compatible_engine() {
+        let mut data = create_package(&[("1.9.0", None), ("1.10.0", None)], "1.10.0");
+        data.versions
+            .get_mut("1.10.0")
+            .unwrap()
+            .engines
+            .insert(String::from("node"), String::from(">=20"));
+        let options = ResolverOptions {
+            node_version: Some(Version::parse("18.17.0").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(resolve(&data, "^1.0.0", &options), "1.9.0");
+        // Falls back to the incompatible version if nothing else matches
+        assert_eq!(resolve(&data, "^1.10.0", &options), "1.10.0");
+    }
+
+    #[test]
+    fn skip_incompatible_optional_dependency() {
+        let npm_db = create_test_db(Default::default());
+        let write = |name: &str, version_data: MinimalPackageVersionData| {
+            let mut data = MinimalPackageData {
+                name: String::from(name),
+                ..Default::default()
+            };
+            data.dist_tags
+                .insert(String::from("latest"), String::from("1.0.0"));
+            data.versions.insert(String::from("1.0.0"), version_data);
+            npm_db.write_package(data).unwrap();
+        };
+        let optional_deps = BTreeMap::from([(String::from("fsevents"), String::from("^1.0.0"))]);
+        write(
+            "chokidar",
+            MinimalPackageVersionData {
+                dependencies: optional_deps.clone(),
+                optional_dependencies: optional_deps,
+                ..Default::default()
+            },
+        );
+        write(
+            "fsevents",
+            MinimalPackageVersionData {
+                os: vec![String::from("darwin")],
+                ..Default::default()
+            },
+        );
+        write(
+            "needs-fsevents",
+            MinimalPackageVersionData {
+                dependencies: BTreeMap::from([(String::from("fsevents"), String::from("^1.0.0"))]),
+                ..Default::default()
+            },
+        );
+
+        let options = ResolverOptions {
+            platform: Some(TargetPlatform::Native {
+                os: String::from("linux"),
+                cpu: String::from("x64"),
+            }),
+            ..Default::default()
+        };
+        let resolve_tree = |names: &[&str]| {
+            let mut tree_builder = DepTreeBuilder::new(npm_db.clone(), options.clone());
+            let deps = names
+                .iter()
+                .map(|name| {
+                    DepRequest::from_name_version(name.to_string(), String::from("^1.0.0")).unwrap()
+                })
+                .collect();
+            tree_builder.resolve_tree(deps).unwrap();
+            tree_builder.resolutions
+        };
+
+        let resolutions = resolve_tree(&["chokidar"]);
+        assert!(resolutions.contains_key("chokidar@1"));
+        assert!(!resolutions.contains_key("fsevents@1"));
+
+        // Still installed when another package requires it
+        let resolutions = resolve_tree(&["chokidar", "needs-fsevents"]);
+        assert!(resolutions.contains_key("fsevents@1"));
+    }
 }
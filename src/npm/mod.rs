@@ -1,3 +1,7 @@
 pub mod package_content;
 pub mod dep_tree_builder;
 pub mod package_data;
+pub mod platform;
+pub mod prefetcher;
+pub mod registries;
+pub mod request_stats;
@@ -1,17 +1,36 @@
 use std::io::{Cursor, Read};
-use std::{fmt, sync::Arc, time::Duration};
+use std::sync::{Arc, Weak};
+use std::{fmt, time::Duration};
 
-use crate::{app_error::ServerError, cached::Cached, npm_replicator::registry::NpmRocksDB};
+use crate::{
+    app_error::ServerError,
+    cached::{CachePolicy, Cached},
+    config::TarballCacheConfig,
+    npm_replicator::registry::NpmRocksDB,
+};
+
+use super::registries::RegistryConfig;
 use ::tar::{Archive, EntryType};
 use flate2::read::GzDecoder;
 use moka::future::Cache;
+use opentelemetry::global;
+use opentelemetry::metrics::Unit;
 use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
 use reqwest_retry::{policies::ExponentialBackoff, RetryTransientMiddleware};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 pub type ByteVec = Vec<u8>;
 pub type FileMap = Arc<HashMap<String, ByteVec>>;
 
+/// Amount of bytes the unpacked files take up
+pub fn get_file_map_size(files: &FileMap) -> usize {
+    files
+        .iter()
+        .map(|(path, content)| path.len() + content.len())
+        .sum()
+}
+
 #[tracing::instrument(name = "accumulate_files", skip(archive))]
 fn accumulate_files<R: Read>(
     mut archive: Archive<R>,
@@ -41,12 +60,17 @@ fn accumulate_files<R: Read>(
     Ok(collected)
 }
 
-#[tracing::instrument(name = "download_tarball", skip(client))]
+#[tracing::instrument(name = "download_tarball", skip(client, auth_token))]
 async fn download_tarball(
     client: &ClientWithMiddleware,
     url: &str,
+    auth_token: Option<&str>,
 ) -> Result<FileMap, ServerError> {
-    let response = client.get(url).send().await?;
+    let mut request = client.get(url);
+    if let Some(auth_token) = auth_token {
+        request = request.bearer_auth(auth_token);
+    }
+    let response = request.send().await?;
     let response_status = response.status();
     if !response_status.is_success() {
         return Err(ServerError::TarballDownloadError {
@@ -67,17 +91,19 @@ async fn download_tarball(
     Ok(Arc::new(files?))
 }
 
-#[tracing::instrument(name = "get_tarball", skip(client, cached))]
+#[tracing::instrument(name = "get_tarball", skip(client, cached, auth_token))]
 async fn get_tarball(
     url: &str,
     client: ClientWithMiddleware,
     cached: Cached<FileMap>,
+    auth_token: Option<String>,
 ) -> Result<FileMap, ServerError> {
     let url_string = String::from(url);
     let res = cached
         .get_cached(|_last_val| {
             Box::pin(async move {
-                let content = download_tarball(&client, url_string.as_str()).await?;
+                let content =
+                    download_tarball(&client, url_string.as_str(), auth_token.as_deref()).await?;
                 Ok::<_, ServerError>(content)
             })
         })
@@ -103,22 +129,81 @@ fn get_client() -> ClientWithMiddleware {
         .build()
 }
 
+#[derive(Clone)]
+struct TarballCacheEntry {
+    cached: Cached<FileMap>,
+    weight: u32,
+    // The files the weight was computed from, a refreshed entry has to be weighed again
+    weighed_files: Weak<HashMap<String, ByteVec>>,
+}
+
+impl TarballCacheEntry {
+    // Tarballs that are still downloading weigh an estimate, so concurrent downloads count against the budget before their size is known
+    fn new(cached: Cached<FileMap>, downloading_weight: u32) -> TarballCacheEntry {
+        TarballCacheEntry {
+            cached,
+            weight: downloading_weight,
+            weighed_files: Weak::new(),
+        }
+    }
+
+    fn with_files(cached: Cached<FileMap>, files: &FileMap) -> TarballCacheEntry {
+        TarballCacheEntry {
+            cached,
+            weight: u32::try_from(get_file_map_size(files)).unwrap_or(u32::MAX),
+            weighed_files: Arc::downgrade(files),
+        }
+    }
+
+    fn is_weighed(&self, files: &FileMap) -> bool {
+        std::ptr::eq(self.weighed_files.as_ptr(), Arc::as_ptr(files))
+    }
+}
+
+/// Cached tarballs weigh their unpacked size
+fn weigh_tarball(_key: &String, entry: &TarballCacheEntry) -> u32 {
+    entry.weight
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct TarballCacheUsage {
+    pub entry_count: u64,
+    // Bytes used by the cached tarballs
+    pub size: u64,
+    pub max_size: u64,
+}
+
 #[derive(Clone)]
 pub struct PackageContentFetcher {
-    cache: Cache<String, Cached<FileMap>>,
-    refresh_interval: Duration,
+    cache: Cache<String, TarballCacheEntry>,
+    max_size: u64,
+    downloading_weight: u32,
+    cache_policy: CachePolicy,
+    registries: Arc<RegistryConfig>,
 }
 
 impl PackageContentFetcher {
-    pub fn new() -> PackageContentFetcher {
-        let ttl = Duration::from_secs(86400);
-        let max_capacity = 50;
+    /// Tarballs are weighed by their unpacked size, the cache evicts them once they take up more than max_size bytes
+    pub fn new(
+        registries: Arc<RegistryConfig>,
+        config: &TarballCacheConfig,
+    ) -> PackageContentFetcher {
         PackageContentFetcher {
             cache: Cache::builder()
-                .max_capacity(max_capacity)
-                .time_to_idle(ttl)
+                .max_capacity(config.max_size)
+                .weigher(weigh_tarball)
+                .time_to_idle(Duration::from_secs(config.time_to_idle))
                 .build(),
-            refresh_interval: Duration::from_secs(604800),
+            max_size: config.max_size,
+            downloading_weight: config.downloading_weight,
+            cache_policy: CachePolicy {
+                refresh_interval: Duration::from_secs(config.refresh_interval),
+                refresh_jitter: Duration::from_secs(config.refresh_jitter),
+                max_stale: Duration::from_secs(config.max_stale),
+                stale_if_error: config.stale_if_error,
+                retry_delay: Duration::from_secs(config.retry_delay),
+            },
+            registries,
         }
     }
 
@@ -126,12 +211,71 @@ impl PackageContentFetcher {
     pub async fn get(&self, url: &str) -> Result<FileMap, ServerError> {
         let key = String::from(url);
         let client = get_client();
-        if let Some(found_value) = self.cache.get(&key).await {
-            get_tarball(url, client, found_value).await
-        } else {
-            let cached: Cached<FileMap> = Cached::new(self.refresh_interval);
-            self.cache.insert(key, cached.clone()).await;
-            get_tarball(url, client, cached).await
+        let auth_token = self
+            .registries
+            .get_tarball_auth_token(url)
+            .map(String::from);
+        let cache_policy = self.cache_policy;
+        let downloading_weight = self.downloading_weight;
+        let entry = self
+            .cache
+            .get_with(key.clone(), async move {
+                TarballCacheEntry::new(Cached::new(cache_policy), downloading_weight)
+            })
+            .await;
+        let files = get_tarball(url, client, entry.cached.clone(), auth_token).await?;
+        // Entries are only weighed when they're inserted, so insert it again once it has been downloaded or refreshed
+        if !entry.is_weighed(&files) {
+            self.cache
+                .insert(key, TarballCacheEntry::with_files(entry.cached, &files))
+                .await;
+        }
+        Ok(files)
+    }
+
+    /// Whether the tarball was downloaded already, without fetching it
+    pub async fn is_cached(&self, url: &str) -> bool {
+        match self.cache.get(url).await {
+            Some(entry) => entry.cached.get_value().is_some(),
+            None => false,
+        }
+    }
+
+    pub fn get_usage(&self) -> TarballCacheUsage {
+        TarballCacheUsage {
+            entry_count: self.cache.entry_count(),
+            size: self.cache.weighted_size(),
+            max_size: self.max_size,
+        }
+    }
+
+    /// Reports the usage as gauges on the global meter, these are read whenever the metrics get collected
+    pub fn register_metrics(&self) {
+        let meter = global::meter("sandpack-cdn");
+        let size = meter
+            .u64_observable_gauge("tarball_cache.size")
+            .with_description("Bytes used by the cached tarballs")
+            .with_unit(Unit::new("By"))
+            .init();
+        let entry_count = meter
+            .u64_observable_gauge("tarball_cache.entry_count")
+            .with_description("Amount of cached tarballs")
+            .init();
+        let max_size = meter
+            .u64_observable_gauge("tarball_cache.max_size")
+            .with_description("Bytes the cached tarballs can take up")
+            .with_unit(Unit::new("By"))
+            .init();
+
+        let fetcher = self.clone();
+        let result = meter.register_callback(move |cx| {
+            let usage = fetcher.get_usage();
+            size.observe(cx, usage.size, &[]);
+            entry_count.observe(cx, usage.entry_count, &[]);
+            max_size.observe(cx, usage.max_size, &[]);
+        });
+        if let Err(err) = result {
+            println!("[Tarball-Cache] Failed to register metrics: {}", err);
         }
     }
 }
@@ -142,6 +286,21 @@ impl fmt::Debug for PackageContentFetcher {
     }
 }
 
+pub async fn get_tarball_url(
+    package_name: &str,
+    version: &str,
+    npm_db: &NpmRocksDB,
+) -> Result<Option<String>, ServerError> {
+    match npm_db.get_package_async(package_name).await {
+        Ok(manifest) => Ok(manifest
+            .versions
+            .get(version)
+            .map(|version_data| version_data.tarball.clone())),
+        Err(ServerError::PackageNotFound(_)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
 #[tracing::instrument(name = "download_package_content", skip(npm_db, content_fetcher))]
 pub async fn download_package_content(
     package_name: &str,
@@ -149,14 +308,76 @@ pub async fn download_package_content(
     npm_db: &NpmRocksDB,
     content_fetcher: &PackageContentFetcher,
 ) -> Result<FileMap, ServerError> {
-    let manifest = npm_db.get_package(package_name)?;
-    if let Some(version_data) = manifest.versions.get(version) {
-        let content = content_fetcher.get(version_data.tarball.as_str()).await?;
-        Ok(content)
-    } else {
-        Err(ServerError::PackageVersionNotFound(
+    if npm_db.is_known_missing(package_name, Some(version)) {
+        return Err(ServerError::PackageVersionNotFound(
             String::from(package_name),
             String::from(version),
-        ))
+        ));
+    }
+
+    // The package or version might have been published after the last replicated change
+    let mut tarball = get_tarball_url(package_name, version, npm_db).await?;
+    if tarball.is_none() {
+        npm_db.fetch_missing_pkg(package_name).await?;
+        tarball = get_tarball_url(package_name, version, npm_db).await?;
+    }
+
+    match tarball {
+        Some(tarball) => content_fetcher.get(tarball.as_str()).await,
+        None => {
+            npm_db.mark_missing(package_name, Some(version));
+            Err(ServerError::PackageVersionNotFound(
+                String::from(package_name),
+                String::from(version),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::TarballCacheConfig;
+    use crate::utils::test_utils::{TestPackage, TestRegistry};
+
+    use super::*;
+
+    #[test]
+    fn file_map_size() {
+        let files: FileMap = Arc::new(HashMap::from([
+            (String::from("/index.js"), b"module.exports = 1;".to_vec()),
+            (String::from("/package.json"), b"{}".to_vec()),
+        ]));
+        assert_eq!(get_file_map_size(&files), 9 + 19 + 13 + 2);
+        assert_eq!(get_file_map_size(&Arc::new(HashMap::new())), 0);
+    }
+
+    #[tokio::test]
+    async fn weighs_downloaded_tarballs() {
+        let registry = TestRegistry::spawn(vec![TestPackage {
+            name: "react",
+            version: "18.2.0",
+            dependencies: vec![],
+            files: vec![("index.js", "module.exports = 1;")],
+        }]);
+        let config = TarballCacheConfig::default();
+        let fetcher = PackageContentFetcher::new(Arc::new(registry.get_registries()), &config);
+        let url = format!("{}react/-/18.2.0.tgz", registry.url);
+
+        // Tarballs that are still downloading weigh an estimate
+        let cached = Cached::new(fetcher.cache_policy);
+        let entry = TarballCacheEntry::new(cached.clone(), config.downloading_weight);
+        assert_eq!(weigh_tarball(&url, &entry), config.downloading_weight);
+
+        let files = fetcher.get(&url).await.unwrap();
+        assert!(fetcher.is_cached(&url).await);
+        fetcher.cache.run_pending_tasks().await;
+        let usage = fetcher.get_usage();
+        assert_eq!(usage.entry_count, 1);
+        assert_eq!(usage.size, get_file_map_size(&files) as u64);
+
+        // Refreshed files are weighed again
+        let entry = TarballCacheEntry::with_files(cached, &files);
+        assert!(entry.is_weighed(&files));
+        assert!(!entry.is_weighed(&Arc::new((*files).clone())));
     }
 }
@@ -3,10 +3,12 @@ use std::{collections::BTreeMap, time::Duration};
 use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
 use reqwest_retry::{policies::ExponentialBackoff, RetryTransientMiddleware};
 use serde::{Deserialize, Serialize};
-use serde_with::serde_as;
+use serde_with::{serde_as, DefaultOnError};
 
 use crate::app_error::ServerError;
 
+use super::registries::Registry;
+
 fn get_client() -> ClientWithMiddleware {
     let retry_policy = ExponentialBackoff::builder().build_with_max_retries(3);
 
@@ -36,6 +38,21 @@ pub struct PackageVersion {
     pub dist: PackageDist,
     #[serde(default)]
     pub dependencies: BTreeMap<String, String>,
+    #[serde(default, rename = "optionalDependencies")]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    pub optional_dependencies: BTreeMap<String, String>,
+    #[serde(default)]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    pub deprecated: Option<String>,
+    #[serde(default)]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    pub engines: BTreeMap<String, String>,
+    #[serde(default)]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    pub os: Vec<String>,
+    #[serde(default)]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    pub cpu: Vec<String>,
 }
 
 #[serde_as]
@@ -50,19 +67,24 @@ pub struct PackageMetadata {
     pub versions: BTreeMap<String, PackageVersion>,
 }
 
-#[tracing::instrument(name = "download_pkg_metadata")]
-pub async fn download_pkg_metadata(pkg_name: &str) -> Result<PackageMetadata, ServerError> {
-    let url: String = format!("https://registry.npmjs.org/{}", pkg_name);
+#[tracing::instrument(name = "download_pkg_metadata", skip(registry))]
+pub async fn download_pkg_metadata(
+    pkg_name: &str,
+    registry: &Registry,
+) -> Result<PackageMetadata, ServerError> {
+    let url: String = registry.get_package_url(pkg_name);
     let client = get_client();
-    let response = client
+    let mut request = client
         .get(&url)
         // Return a minimal version of the package metadata
         .header(
             "Accept",
             "application/vnd.npm.install-v1+json; q=1.0, application/json; q=0.8, */*",
-        )
-        .send()
-        .await?;
+        );
+    if let Some(auth_token) = &registry.auth_token {
+        request = request.bearer_auth(auth_token);
+    }
+    let response = request.send().await?;
     let response_status = response.status();
     if !response_status.is_success() {
         return Err(ServerError::PackageMetadataDownloadError {
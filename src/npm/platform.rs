@@ -0,0 +1,143 @@
+use std::fmt;
+
+use node_semver::{Range, Version};
+use serde::Deserialize;
+
+use crate::npm_replicator::types::document::MinimalPackageVersionData;
+
+#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(try_from = "String")]
+pub enum TargetPlatform {
+    Browser,
+    WebContainer,
+    // Example: linux-x64 => os linux and cpu x64
+    Native { os: String, cpu: String },
+}
+
+impl TryFrom<String> for TargetPlatform {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "browser" => Ok(TargetPlatform::Browser),
+            "webcontainer" => Ok(TargetPlatform::WebContainer),
+            _ => match value.split_once('-') {
+                Some((os, cpu)) if !os.is_empty() && !cpu.is_empty() => {
+                    Ok(TargetPlatform::Native {
+                        os: String::from(os),
+                        cpu: String::from(cpu),
+                    })
+                }
+                _ => Err(format!("Invalid platform {}", value)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for TargetPlatform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TargetPlatform::Browser => write!(f, "browser"),
+            TargetPlatform::WebContainer => write!(f, "webcontainer"),
+            TargetPlatform::Native { os, cpu } => write!(f, "{}-{}", os, cpu),
+        }
+    }
+}
+
+// Follows npm's `os` and `cpu` semantics, entries can be negated like `!win32`
+fn matches_list(list: &[String], value: &str) -> bool {
+    if list.is_empty() {
+        return true;
+    }
+
+    let mut has_allowed = false;
+    for entry in list {
+        match entry.strip_prefix('!') {
+            Some(blocked) => {
+                if blocked == value {
+                    return false;
+                }
+            }
+            None => {
+                if entry == value {
+                    return true;
+                }
+                has_allowed = true;
+            }
+        }
+    }
+    !has_allowed
+}
+
+/// Returns why a version can't be installed on the target, if it can't be
+pub fn get_incompatibility(
+    data: &MinimalPackageVersionData,
+    platform: Option<&TargetPlatform>,
+    node_version: Option<&Version>,
+) -> Option<String> {
+    if let Some(platform) = platform {
+        let is_supported = match platform {
+            // Native binaries can't run in the browser or a webcontainer
+            TargetPlatform::Browser | TargetPlatform::WebContainer => {
+                data.os.is_empty() && data.cpu.is_empty()
+            }
+            TargetPlatform::Native { os, cpu } => {
+                matches_list(&data.os, os) && matches_list(&data.cpu, cpu)
+            }
+        };
+        if !is_supported {
+            return Some(format!(
+                "Unsupported platform {}, requires os {:?} and cpu {:?}",
+                platform, data.os, data.cpu
+            ));
+        }
+    }
+
+    if let (Some(node_version), Some(node_range)) = (node_version, data.engines.get("node")) {
+        // Plenty of packages have invalid engine ranges, npm ignores those as well
+        if let Ok(range) = Range::parse(node_range) {
+            if !range.satisfies(node_version) {
+                return Some(format!(
+                    "Unsupported engine node@{}, requires node {}",
+                    node_version, node_range
+                ));
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_list(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_platform() {
+        assert_eq!(
+            TargetPlatform::try_from(String::from("linux-x64")),
+            Ok(TargetPlatform::Native {
+                os: String::from("linux"),
+                cpu: String::from("x64"),
+            })
+        );
+        assert_eq!(
+            TargetPlatform::try_from(String::from("browser")),
+            Ok(TargetPlatform::Browser)
+        );
+        assert!(TargetPlatform::try_from(String::from("linux")).is_err());
+    }
+
+    #[test]
+    fn platform_lists() {
+        assert!(matches_list(&to_list(&[]), "linux"));
+        assert!(matches_list(&to_list(&["linux", "darwin"]), "linux"));
+        assert!(!matches_list(&to_list(&["darwin"]), "linux"));
+        assert!(matches_list(&to_list(&["!win32"]), "linux"));
+        assert!(!matches_list(&to_list(&["!win32"]), "win32"));
+    }
+}
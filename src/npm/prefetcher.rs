@@ -0,0 +1,280 @@
+use std::collections::HashSet;
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use tokio::sync::{broadcast, Semaphore};
+use tokio::time::sleep;
+
+use crate::app_error::{AppResult, ServerError};
+use crate::npm_replicator::hooks::{PackageEvent, PackageEventKind, ReplicationHooks};
+use crate::npm_replicator::registry::NpmRocksDB;
+use crate::utils::token_bucket::{TokenBucket, TokenBucketConfig};
+
+use super::package_content::{get_file_map_size, get_tarball_url, PackageContentFetcher};
+use super::request_stats::RequestStats;
+
+#[derive(Clone, Debug)]
+pub struct PrefetchConfig {
+    // Packages that are always prefetched
+    pub allowlist: HashSet<String>,
+    // Packages with a request score of at least this are prefetched as well, 0 turns this off
+    pub hot_threshold: f64,
+    // Amount of tarballs downloaded at the same time
+    pub concurrency: usize,
+    // Bytes per second
+    pub bandwidth: f64,
+}
+
+fn parse_env<T: std::str::FromStr>(name: &str, default: T) -> AppResult<T> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| ServerError::InvalidConfig(format!("{} should be a number", name))),
+        Err(_) => Ok(default),
+    }
+}
+
+impl PrefetchConfig {
+    /// Reads PREFETCH_PACKAGES (comma separated), PREFETCH_HOT_THRESHOLD, PREFETCH_CONCURRENCY and PREFETCH_BANDWIDTH
+    pub fn from_env() -> AppResult<PrefetchConfig> {
+        Ok(PrefetchConfig {
+            allowlist: env::var("PREFETCH_PACKAGES")
+                .unwrap_or_default()
+                .split(',')
+                .map(|name| name.trim())
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+                .collect(),
+            hot_threshold: parse_env("PREFETCH_HOT_THRESHOLD", 20.0)?,
+            concurrency: parse_env("PREFETCH_CONCURRENCY", 2)?,
+            bandwidth: parse_env("PREFETCH_BANDWIDTH", 5_000_000.0)?,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.concurrency > 0 && (!self.allowlist.is_empty() || self.hot_threshold > 0.0)
+    }
+
+    fn should_prefetch(&self, pkg_name: &str, request_stats: &RequestStats) -> bool {
+        self.allowlist.contains(pkg_name)
+            || (self.hot_threshold > 0.0 && request_stats.get_score(pkg_name) >= self.hot_threshold)
+    }
+}
+
+// Used until there are downloaded sizes to estimate from
+const INITIAL_SIZE_ESTIMATE: f64 = 1_000_000.0;
+
+/// The size of a download is only known once it's done, so every download reserves an estimate before it starts
+/// and settles the difference afterwards. Concurrent downloads can't all start while the budget is still positive that way
+#[derive(Debug)]
+struct BandwidthBudget {
+    bucket: TokenBucket,
+    // Moving average of the downloaded sizes
+    size_estimate: f64,
+}
+
+impl BandwidthBudget {
+    fn new(bandwidth: f64, now: Instant) -> BandwidthBudget {
+        BandwidthBudget {
+            bucket: TokenBucket::new(
+                TokenBucketConfig {
+                    burst: bandwidth,
+                    refill_rate: bandwidth,
+                },
+                now,
+            ),
+            size_estimate: INITIAL_SIZE_ESTIMATE,
+        }
+    }
+
+    /// Reserves the estimated size once earlier downloads are paid off, otherwise returns how long that takes
+    fn try_reserve(&mut self, now: Instant) -> Result<f64, Duration> {
+        let wait = self.bucket.time_until_available(now);
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        self.bucket.consume(self.size_estimate, now);
+        Ok(self.size_estimate)
+    }
+
+    /// Charges the actual size instead of the reserved estimate, failed downloads pass None to get the reservation back
+    fn settle(&mut self, reserved: f64, size: Option<f64>, now: Instant) {
+        match size {
+            Some(size) => {
+                if size > reserved {
+                    self.bucket.consume(size - reserved, now);
+                } else {
+                    self.bucket.refund(reserved - size, now);
+                }
+                self.size_estimate = 0.8 * self.size_estimate + 0.2 * size;
+            }
+            None => self.bucket.refund(reserved, now),
+        }
+    }
+}
+
+async fn prefetch(
+    npm_db: &NpmRocksDB,
+    content_fetcher: &PackageContentFetcher,
+    bandwidth: &Mutex<BandwidthBudget>,
+    pkg_name: &str,
+    version: &str,
+) {
+    // The event is sent once the package is written, so the version is in the db
+    let tarball = match get_tarball_url(pkg_name, version, npm_db).await {
+        Ok(Some(tarball)) => tarball,
+        Ok(None) => return,
+        Err(err) => {
+            println!(
+                "[Prefetch] Failed to prefetch {}@{}: {:?}",
+                pkg_name, version, err
+            );
+            return;
+        }
+    };
+    // Nothing gets downloaded for tarballs that are cached already, so those don't count against the budget
+    if content_fetcher.is_cached(&tarball).await {
+        return;
+    }
+
+    let reserved = loop {
+        let reserved = bandwidth.lock().try_reserve(Instant::now());
+        match reserved {
+            Ok(reserved) => break reserved,
+            Err(wait) => sleep(wait).await,
+        }
+    };
+
+    match content_fetcher.get(&tarball).await {
+        Ok(files) => {
+            // The unpacked size, which overestimates what went over the wire
+            let size = get_file_map_size(&files);
+            bandwidth
+                .lock()
+                .settle(reserved, Some(size as f64), Instant::now());
+            println!("[Prefetch] Prefetched {}@{}", pkg_name, version);
+        }
+        Err(err) => {
+            bandwidth.lock().settle(reserved, None, Instant::now());
+            println!(
+                "[Prefetch] Failed to prefetch {}@{}: {:?}",
+                pkg_name, version, err
+            );
+        }
+    }
+}
+
+/// Downloads the new latest tarball of popular packages as soon as it's replicated,
+/// so the first request for it doesn't have to wait on npm
+pub fn spawn_prefetcher(
+    npm_db: NpmRocksDB,
+    content_fetcher: PackageContentFetcher,
+    request_stats: RequestStats,
+    hooks: &ReplicationHooks,
+    config: PrefetchConfig,
+) {
+    if !config.is_enabled() {
+        return;
+    }
+
+    println!(
+        "[Prefetch] Prefetching {} packages and packages with a request score of at least {}",
+        config.allowlist.len(),
+        config.hot_threshold
+    );
+    let mut receiver = hooks.subscribe();
+    let semaphore = Arc::new(Semaphore::new(config.concurrency));
+    let bandwidth = Arc::new(Mutex::new(BandwidthBudget::new(
+        config.bandwidth,
+        Instant::now(),
+    )));
+    tokio::task::spawn(async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(count)) => {
+                    println!("[Prefetch] Over budget, skipped {} events", count);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            let PackageEvent {
+                kind, name, latest, ..
+            } = event;
+            let version = match (kind, latest) {
+                (PackageEventKind::Published, Some(latest)) => latest,
+                _ => continue,
+            };
+            if !config.should_prefetch(&name, &request_stats) {
+                continue;
+            }
+
+            // Waiting here instead of in the task keeps the amount of queued prefetches bounded
+            let permit = match semaphore.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => break,
+            };
+            let npm_db = npm_db.clone();
+            let content_fetcher = content_fetcher.clone();
+            let bandwidth = bandwidth.clone();
+            tokio::task::spawn(async move {
+                prefetch(&npm_db, &content_fetcher, &bandwidth, &name, &version).await;
+                drop(permit);
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefetch_selection() {
+        let request_stats = RequestStats::default();
+        for _ in 0..3 {
+            request_stats.record_request("react");
+        }
+        request_stats.record_request("left-pad");
+
+        let config = PrefetchConfig {
+            allowlist: HashSet::from([String::from("vue")]),
+            hot_threshold: 2.5,
+            concurrency: 2,
+            bandwidth: 1000.0,
+        };
+        assert!(config.should_prefetch("vue", &request_stats));
+        assert!(config.should_prefetch("react", &request_stats));
+        assert!(!config.should_prefetch("left-pad", &request_stats));
+
+        let allowlist_only = PrefetchConfig {
+            hot_threshold: 0.0,
+            ..config
+        };
+        assert!(!allowlist_only.should_prefetch("react", &request_stats));
+    }
+
+    #[test]
+    fn bandwidth_reservations() {
+        let now = Instant::now();
+        let mut budget = BandwidthBudget::new(1_500_000.0, now);
+
+        // The second download has to wait on the first one's reservation
+        let reserved = budget.try_reserve(now).unwrap();
+        assert!(budget.try_reserve(now).is_ok());
+        assert!(budget.try_reserve(now).is_err());
+
+        // Settling a smaller download gives back the difference
+        budget.settle(reserved, Some(0.0), now);
+        assert!(budget.try_reserve(now).is_ok());
+        assert_eq!(budget.size_estimate, 0.8 * INITIAL_SIZE_ESTIMATE);
+
+        // Failed downloads don't count
+        let mut budget = BandwidthBudget::new(1_000_000.0, now);
+        let reserved = budget.try_reserve(now).unwrap();
+        budget.settle(reserved, None, now);
+        assert_eq!(budget.bucket.time_until_available(now), Duration::ZERO);
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::{env, fmt, fs};
+
+const NPM_REGISTRY_URL: &str = "https://registry.npmjs.org/";
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Registry {
+    // Always ends with a slash
+    pub url: String,
+    pub auth_token: Option<String>,
+}
+
+impl Registry {
+    fn new(url: &str) -> Registry {
+        let mut url = String::from(url.trim());
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        Registry {
+            url,
+            auth_token: None,
+        }
+    }
+
+    pub fn get_package_url(&self, pkg_name: &str) -> String {
+        format!("{}{}", self.url, pkg_name)
+    }
+
+    // npmrc keys auth tokens by the registry url without the protocol, eg. //npm.acme.com/
+    fn get_auth_key(&self) -> &str {
+        match self.url.find("//") {
+            Some(idx) => &self.url[idx..],
+            None => &self.url,
+        }
+    }
+}
+
+// Never log the auth token
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Registry")
+            .field("url", &self.url)
+            .field("has_auth_token", &self.auth_token.is_some())
+            .finish()
+    }
+}
+
+/// Routes packages to registries by scope, configured like `.npmrc`:
+///
+/// registry=https://registry.npmjs.org/
+/// @acme:registry=https://npm.acme.com/
+/// //npm.acme.com/:_authToken=${ACME_NPM_TOKEN}
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegistryConfig {
+    default: Registry,
+    scoped: HashMap<String, Registry>,
+}
+
+impl Default for RegistryConfig {
+    fn default() -> Self {
+        RegistryConfig {
+            default: Registry::new(NPM_REGISTRY_URL),
+            scoped: HashMap::new(),
+        }
+    }
+}
+
+// Replaces ${ENV_VAR} with the value of the env variable, like npm does
+fn expand_env_vars(value: &str) -> String {
+    let mut result = String::new();
+    let mut remaining = value;
+    while let Some(start) = remaining.find("${") {
+        result.push_str(&remaining[..start]);
+        match remaining[start..].find('}') {
+            Some(end) => {
+                let var_name = &remaining[start + 2..start + end];
+                result.push_str(&env::var(var_name).unwrap_or_default());
+                remaining = &remaining[start + end + 1..];
+            }
+            None => {
+                result.push_str(&remaining[start..]);
+                remaining = "";
+            }
+        }
+    }
+    result.push_str(remaining);
+    result
+}
+
+impl RegistryConfig {
+    pub fn from_npmrc(content: &str) -> RegistryConfig {
+        let mut config = RegistryConfig::default();
+        let mut auth_tokens: HashMap<String, String> = HashMap::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), expand_env_vars(value.trim())),
+                None => continue,
+            };
+
+            if key == "registry" {
+                config.default = Registry::new(&value);
+            } else if let Some(scope) = key.strip_suffix(":registry") {
+                config
+                    .scoped
+                    .insert(String::from(scope), Registry::new(&value));
+            } else if let Some(auth_key) = key.strip_suffix(":_authToken") {
+                let mut auth_key = String::from(auth_key);
+                if !auth_key.ends_with('/') {
+                    auth_key.push('/');
+                }
+                auth_tokens.insert(auth_key, value);
+            }
+        }
+
+        for registry in config
+            .scoped
+            .values_mut()
+            .chain(std::iter::once(&mut config.default))
+        {
+            registry.auth_token = auth_tokens.get(registry.get_auth_key()).cloned();
+        }
+
+        config
+    }
+
+    /// Reads the npmrc file defined in NPMRC_PATH, falls back to only using the npm registry
+    pub fn from_env() -> RegistryConfig {
+        match env::var("NPMRC_PATH") {
+            Ok(npmrc_path) => {
+                let content = fs::read_to_string(&npmrc_path)
+                    .unwrap_or_else(|_| panic!("Could not read npmrc file {}", npmrc_path));
+                RegistryConfig::from_npmrc(&content)
+            }
+            Err(_) => RegistryConfig::default(),
+        }
+    }
+
+    fn get_scope(pkg_name: &str) -> Option<&str> {
+        if pkg_name.starts_with('@') {
+            pkg_name.split_once('/').map(|(scope, _)| scope)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_registry(&self, pkg_name: &str) -> &Registry {
+        RegistryConfig::get_scope(pkg_name)
+            .and_then(|scope| self.scoped.get(scope))
+            .unwrap_or(&self.default)
+    }
+
+    /// Packages in a scope with its own registry are private,
+    /// these should never come from or end up in the public npm changes feed
+    pub fn is_private(&self, pkg_name: &str) -> bool {
+        RegistryConfig::get_scope(pkg_name)
+            .map(|scope| self.scoped.contains_key(scope))
+            .unwrap_or(false)
+    }
+
+    /// Finds the auth token for a tarball url, based on the registry it's hosted on
+    pub fn get_tarball_auth_token(&self, url: &str) -> Option<&str> {
+        self.scoped
+            .values()
+            .chain(std::iter::once(&self.default))
+            .filter(|registry| url.starts_with(&registry.url))
+            .max_by_key(|registry| registry.url.len())
+            .and_then(|registry| registry.auth_token.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scoped_registries() {
+        env::set_var("SANDPACK_TEST_NPM_TOKEN", "secret");
+        let config = RegistryConfig::from_npmrc(
+            "
+            # Private packages
+            @acme:registry=https://npm.acme.com
+            //npm.acme.com/:_authToken=${SANDPACK_TEST_NPM_TOKEN}
+            ",
+        );
+
+        assert!(config.is_private("@acme/button"));
+        assert!(!config.is_private("@babel/core"));
+        assert!(!config.is_private("react"));
+
+        let registry = config.get_registry("@acme/button");
+        assert_eq!(registry.url, "https://npm.acme.com/");
+        assert_eq!(registry.auth_token, Some(String::from("secret")));
+        assert_eq!(
+            config.get_registry("react").get_package_url("react"),
+            "https://registry.npmjs.org/react"
+        );
+
+        assert_eq!(
+            config.get_tarball_auth_token("https://npm.acme.com/@acme/button/-/button-1.0.0.tgz"),
+            Some("secret")
+        );
+        assert_eq!(
+            config.get_tarball_auth_token("https://registry.npmjs.org/react/-/react-18.2.0.tgz"),
+            None
+        );
+    }
+}
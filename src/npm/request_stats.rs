@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+use crate::app_error::AppResult;
+use crate::npm_replicator::registry::NpmRocksDB;
+use crate::utils::time::secs_since_epoch;
+
+// A request counts half as much after a day
+const HALF_LIFE: u64 = 86400;
+// Counters that decayed below this are forgotten
+const MIN_SCORE: f64 = 0.01;
+// New packages aren't tracked while this many are, until pruning makes room again
+const MAX_TRACKED_PACKAGES: usize = 100_000;
+// Pruning leaves room below the maximum, so new packages can be tracked until the next prune
+const PRUNED_PACKAGES: usize = 90_000;
+const PERSIST_INTERVAL: Duration = Duration::from_secs(300);
+
+/// A request count that decays exponentially over time, so recent requests count more than old ones
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct DecayedCounter {
+    value: f64,
+    // Seconds since epoch
+    updated_at: u64,
+}
+
+impl DecayedCounter {
+    fn get_value(&self, now: u64) -> f64 {
+        let elapsed = now.saturating_sub(self.updated_at) as f64;
+        self.value * 0.5f64.powf(elapsed / HALF_LIFE as f64)
+    }
+
+    fn increment(&mut self, now: u64) {
+        self.value = self.get_value(now) + 1.0;
+        self.updated_at = now;
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct PackageScore {
+    pub name: String,
+    pub score: f64,
+}
+
+/// Tracks how often packages are requested, to find out which packages are popular
+#[derive(Clone, Debug, Default)]
+pub struct RequestStats {
+    counters: Arc<Mutex<HashMap<String, DecayedCounter>>>,
+}
+
+impl RequestStats {
+    pub fn load(npm_db: &NpmRocksDB) -> AppResult<RequestStats> {
+        let counters = npm_db.get_request_stats()?.unwrap_or_default();
+        Ok(RequestStats {
+            counters: Arc::new(Mutex::new(counters)),
+        })
+    }
+
+    pub fn record_request(&self, pkg_name: &str) {
+        self.record_request_at(pkg_name, secs_since_epoch());
+    }
+
+    fn record_request_at(&self, pkg_name: &str, now: u64) {
+        let mut counters = self.counters.lock();
+        match counters.get_mut(pkg_name) {
+            Some(counter) => counter.increment(now),
+            None => {
+                if counters.len() >= MAX_TRACKED_PACKAGES {
+                    return;
+                }
+                counters.insert(
+                    String::from(pkg_name),
+                    DecayedCounter {
+                        value: 1.0,
+                        updated_at: now,
+                    },
+                );
+            }
+        }
+    }
+
+    pub fn get_score(&self, pkg_name: &str) -> f64 {
+        self.get_score_at(pkg_name, secs_since_epoch())
+    }
+
+    fn get_score_at(&self, pkg_name: &str, now: u64) -> f64 {
+        self.counters
+            .lock()
+            .get(pkg_name)
+            .map(|counter| counter.get_value(now))
+            .unwrap_or(0.0)
+    }
+
+    fn get_scores(&self, now: u64) -> Vec<PackageScore> {
+        let mut scores: Vec<PackageScore> = self
+            .counters
+            .lock()
+            .iter()
+            .map(|(name, counter)| PackageScore {
+                name: name.clone(),
+                score: counter.get_value(now),
+            })
+            .collect();
+        scores.sort_by(|a, b| b.score.total_cmp(&a.score));
+        scores
+    }
+
+    /// The most requested packages, most popular first
+    pub fn get_top(&self, limit: usize) -> Vec<PackageScore> {
+        let mut scores = self.get_scores(secs_since_epoch());
+        scores.truncate(limit);
+        scores
+    }
+
+    /// Amount of packages that together make up the given share of all requests
+    pub fn get_working_set_size(&self, share: f64) -> usize {
+        let scores = self.get_scores(secs_since_epoch());
+        let total: f64 = scores.iter().map(|pkg| pkg.score).sum();
+        let mut covered = 0.0;
+        for (idx, pkg) in scores.iter().enumerate() {
+            covered += pkg.score;
+            if covered >= total * share {
+                return idx + 1;
+            }
+        }
+        scores.len()
+    }
+
+    /// Forgets packages that haven't been requested in a long time and keeps the least popular ones from piling up
+    fn prune(&self, now: u64) {
+        let mut counters = self.counters.lock();
+        counters.retain(|_, counter| counter.get_value(now) >= MIN_SCORE);
+        if counters.len() > PRUNED_PACKAGES {
+            let mut entries: Vec<(String, DecayedCounter)> = counters.drain().collect();
+            entries.sort_by(|a, b| b.1.get_value(now).total_cmp(&a.1.get_value(now)));
+            entries.truncate(PRUNED_PACKAGES);
+            counters.extend(entries);
+        }
+    }
+
+    async fn persist(&self, npm_db: &NpmRocksDB) -> AppResult<()> {
+        self.prune(secs_since_epoch());
+        let counters = self.counters.lock().clone();
+        let cloned_npm_db = npm_db.clone();
+        npm_db
+            .spawn_blocking(move || cloned_npm_db.put_request_stats(&counters))
+            .await?
+    }
+
+    pub fn spawn_persist_worker(&self, npm_db: NpmRocksDB) {
+        let request_stats = self.clone();
+        tokio::task::spawn(async move {
+            loop {
+                sleep(PERSIST_INTERVAL).await;
+                if let Err(err) = request_stats.persist(&npm_db).await {
+                    println!("[Request-Stats] Failed to persist request stats {:?}", err);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decay_and_ranking() {
+        let mut counter = DecayedCounter {
+            value: 8.0,
+            updated_at: 1000,
+        };
+        assert_eq!(counter.get_value(1000 + HALF_LIFE), 4.0);
+        counter.increment(1000 + 2 * HALF_LIFE);
+        assert_eq!(counter.get_value(1000 + 2 * HALF_LIFE), 3.0);
+
+        let stats = RequestStats::default();
+        for _ in 0..8 {
+            stats.record_request("react");
+        }
+        stats.record_request("vue");
+        stats.record_request("vue");
+        stats.record_request("left-pad");
+        let top: Vec<String> = stats.get_top(2).into_iter().map(|pkg| pkg.name).collect();
+        assert_eq!(top, vec![String::from("react"), String::from("vue")]);
+        assert_eq!(stats.get_working_set_size(0.7), 1);
+        assert_eq!(stats.get_working_set_size(1.0), 3);
+
+        stats.prune(secs_since_epoch() + 30 * HALF_LIFE);
+        assert!(stats.get_top(10).is_empty());
+    }
+
+    #[test]
+    fn max_tracked_packages() {
+        let now = 1000;
+        let stats = RequestStats::default();
+        for idx in 0..MAX_TRACKED_PACKAGES {
+            stats.record_request_at(&format!("pkg-{}", idx), now);
+        }
+        stats.record_request_at("pkg-0", now);
+        stats.record_request_at("react", now);
+        assert_eq!(stats.get_score_at("pkg-0", now), 2.0);
+        assert_eq!(stats.get_score_at("react", now), 0.0);
+
+        stats.prune(now);
+        assert_eq!(stats.counters.lock().len(), PRUNED_PACKAGES);
+        stats.record_request_at("react", now);
+        assert_eq!(stats.get_score_at("react", now), 1.0);
+    }
+}
@@ -1,6 +1,6 @@
 use super::{
     error::{ChangeStreamError, ChangeStreamResult},
-    types::changes::ChangesPage,
+    types::changes::{ChangesPage, DatabaseInfo},
 };
 use reqwest::{Client, Method};
 use std::{collections::HashMap, time::Duration};
@@ -11,6 +11,8 @@ use std::{collections::HashMap, time::Duration};
 /// [1]: https://docs.couchdb.org/en/stable/api/database/changes.html
 const COUCH_MAX_TIMEOUT: usize = 60000;
 
+const REGISTRY_DB_URL: &str = "https://replicate.npmjs.com/registry/";
+
 /// The stream for the `_changes` endpoint.
 ///
 /// This is returned from [Database::changes].
@@ -50,7 +52,7 @@ impl ChangesStream {
             .insert("since".to_string(), self.last_seq.to_string());
         let request = self
             .client
-            .request(Method::GET, "https://replicate.npmjs.com/registry/_changes")
+            .request(Method::GET, format!("{}_changes", REGISTRY_DB_URL))
             .query(&self.params);
         // println!("{:?}", request);
         let res = request.send().await?;
@@ -64,4 +66,14 @@ impl ChangesStream {
         self.last_seq = page.last_seq.into();
         Ok(page)
     }
+
+    /// Fetches the latest sequence of the registry, to know how far behind we are
+    pub async fn fetch_update_seq(&self) -> ChangeStreamResult<i64> {
+        let res = self.client.get(REGISTRY_DB_URL).send().await?;
+        if !res.status().is_success() {
+            return Err(ChangeStreamError::new(res.status().into(), None));
+        }
+        let info: DatabaseInfo = res.json().await?;
+        Ok(info.update_seq)
+    }
 }
@@ -1,3 +1,5 @@
+use std::fmt;
+
 use reqwest::StatusCode;
 
 #[derive(Clone, Debug)]
@@ -12,6 +14,15 @@ impl ChangeStreamError {
     }
 }
 
+impl fmt::Display for ChangeStreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.message {
+            Some(message) => write!(f, "Status {}: {}", self.status, message),
+            None => write!(f, "Status {}", self.status),
+        }
+    }
+}
+
 impl From<reqwest::Error> for ChangeStreamError {
     fn from(e: reqwest::Error) -> Self {
         ChangeStreamError::new(
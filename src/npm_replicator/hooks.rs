@@ -0,0 +1,289 @@
+use std::collections::VecDeque;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
+use reqwest_retry::{policies::ExponentialBackoff, RetryTransientMiddleware};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::utils::time::secs_since_epoch;
+
+// Subscribers that fall further behind than this skip the missed events
+const CHANNEL_CAPACITY: usize = 1024;
+// Amount of recent events kept around for clients that resume after reconnecting
+const BACKLOG_CAPACITY: usize = 1000;
+pub const SIGNATURE_HEADER: &str = "X-Sandpack-Signature";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PackageEventKind {
+    Published,
+    Deleted,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct PackageEvent {
+    // Increases by one for every event, see EventBacklog::new
+    pub id: u64,
+    pub kind: PackageEventKind,
+    pub name: String,
+    // The latest dist-tag after the update, not set for deletions
+    pub latest: Option<String>,
+    // Seconds since epoch
+    pub timestamp: u64,
+}
+
+#[derive(Debug)]
+struct EventBacklog {
+    next_id: u64,
+    // Oldest first
+    events: VecDeque<PackageEvent>,
+}
+
+impl EventBacklog {
+    fn new(started_at: u64) -> EventBacklog {
+        EventBacklog {
+            // Starting from the time in ms keeps ids increasing across restarts,
+            // as long as there's less than one event per ms on average
+            next_id: started_at * 1000,
+            events: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, event: PackageEvent) {
+        self.events.push_back(event);
+        while self.events.len() > BACKLOG_CAPACITY {
+            self.events.pop_front();
+        }
+    }
+
+    /// All events after the given id, or None when some of them are no longer in the backlog
+    fn events_since(&self, since: u64) -> Option<Vec<PackageEvent>> {
+        // Ids that weren't handed out yet, for example when the clock went back during a restart
+        if since >= self.next_id {
+            return None;
+        }
+        let oldest_id = self
+            .events
+            .front()
+            .map(|event| event.id)
+            .unwrap_or(self.next_id);
+        if since + 1 < oldest_id {
+            return None;
+        }
+        Some(
+            self.events
+                .iter()
+                .filter(|event| event.id > since)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+/// Notifies other subsystems and external webhooks whenever a package is written to or deleted from the db
+#[derive(Clone, Debug)]
+pub struct ReplicationHooks {
+    sender: broadcast::Sender<PackageEvent>,
+    backlog: Arc<Mutex<EventBacklog>>,
+}
+
+fn get_client() -> ClientWithMiddleware {
+    let retry_policy = ExponentialBackoff::builder().build_with_max_retries(3);
+
+    let base_client = reqwest::ClientBuilder::new()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("reqwest::ClientBuilder::build()");
+
+    ClientBuilder::new(base_client)
+        .with(RetryTransientMiddleware::new_with_policy(retry_policy))
+        .build()
+}
+
+/// Hex encoded HMAC-SHA256 of the body, so receivers can verify the event came from us
+fn sign_body(key: &hmac::Key, body: &[u8]) -> String {
+    format!("sha256={}", hex::encode(hmac::sign(key, body).as_ref()))
+}
+
+async fn deliver_webhook(
+    client: &ClientWithMiddleware,
+    url: &str,
+    key: Option<&hmac::Key>,
+    event: &PackageEvent,
+) -> Result<(), String> {
+    let body = serde_json::to_vec(event).map_err(|err| err.to_string())?;
+    let mut request = client.post(url).header("Content-Type", "application/json");
+    if let Some(key) = key {
+        request = request.header(SIGNATURE_HEADER, sign_body(key, &body));
+    }
+    let response = request
+        .body(body)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("Responded with status {}", response.status()));
+    }
+    Ok(())
+}
+
+impl ReplicationHooks {
+    pub fn new() -> ReplicationHooks {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        ReplicationHooks {
+            sender,
+            backlog: Arc::new(Mutex::new(EventBacklog::new(secs_since_epoch()))),
+        }
+    }
+
+    /// Reads the webhook urls from REPLICATION_WEBHOOKS, comma separated,
+    /// and signs the requests when REPLICATION_WEBHOOK_SECRET is set
+    pub fn from_env() -> ReplicationHooks {
+        let hooks = ReplicationHooks::new();
+        let key = env::var("REPLICATION_WEBHOOK_SECRET")
+            .ok()
+            .map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()));
+        if let Ok(urls) = env::var("REPLICATION_WEBHOOKS") {
+            for url in urls
+                .split(',')
+                .map(|url| url.trim())
+                .filter(|url| !url.is_empty())
+            {
+                hooks.spawn_webhook(String::from(url), key.clone());
+            }
+        }
+        hooks
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<PackageEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Subscribes to new events, together with the events after `since` that were already sent.
+    /// The backlog is None when it's too old to resume from
+    pub fn subscribe_since(
+        &self,
+        since: u64,
+    ) -> (Option<Vec<PackageEvent>>, broadcast::Receiver<PackageEvent>) {
+        // Holding the lock while subscribing makes sure no event is missed or sent twice
+        let backlog = self.backlog.lock();
+        (backlog.events_since(since), self.sender.subscribe())
+    }
+
+    fn publish(&self, kind: PackageEventKind, name: &str, latest: Option<String>) {
+        let mut backlog = self.backlog.lock();
+        let event = PackageEvent {
+            id: backlog.next_id,
+            kind,
+            name: String::from(name),
+            latest,
+            timestamp: secs_since_epoch(),
+        };
+        backlog.next_id += 1;
+        backlog.push(event.clone());
+        // Sending only fails when nobody is subscribed, which is fine
+        let _ = self.sender.send(event);
+    }
+
+    pub fn on_package_written(&self, name: &str, latest: Option<String>) {
+        self.publish(PackageEventKind::Published, name, latest);
+    }
+
+    pub fn on_package_deleted(&self, name: &str) {
+        self.publish(PackageEventKind::Deleted, name, None);
+    }
+
+    /// Every webhook gets its own subscriber, so a slow endpoint doesn't hold back the others
+    fn spawn_webhook(&self, url: String, key: Option<hmac::Key>) {
+        println!(
+            "[NPM-Replication] Sending package events to webhook {}",
+            url
+        );
+        let mut receiver = self.subscribe();
+        tokio::task::spawn(async move {
+            let client = get_client();
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => {
+                        if let Err(err) = deliver_webhook(&client, &url, key.as_ref(), &event).await
+                        {
+                            println!(
+                                "[NPM-Replication] Failed to deliver event {} to webhook {}: {}",
+                                event.id, url, err
+                            );
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(count)) => {
+                        println!(
+                            "[NPM-Replication] Webhook {} fell behind, skipped {} events",
+                            url, count
+                        );
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+}
+
+impl Default for ReplicationHooks {
+    fn default() -> Self {
+        ReplicationHooks::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publishes_events_in_order() {
+        let hooks = ReplicationHooks::new();
+        let mut receiver = hooks.subscribe();
+        hooks.on_package_written("react", Some(String::from("18.2.0")));
+        hooks.on_package_deleted("left-pad");
+
+        let written = receiver.try_recv().unwrap();
+        assert_eq!(written.kind, PackageEventKind::Published);
+        assert_eq!(written.latest, Some(String::from("18.2.0")));
+        let deleted = receiver.try_recv().unwrap();
+        assert_eq!(deleted.kind, PackageEventKind::Deleted);
+        assert_eq!(deleted.name, "left-pad");
+        assert_eq!(deleted.id, written.id + 1);
+    }
+
+    #[test]
+    fn resume_from_backlog() {
+        let hooks = ReplicationHooks::new();
+        for name in ["a", "b", "c"] {
+            hooks.on_package_deleted(name);
+        }
+        let (backlog, _) = hooks.subscribe_since(0);
+        assert_eq!(backlog, None);
+
+        let first_id = hooks.backlog.lock().events[0].id;
+        let (backlog, _) = hooks.subscribe_since(first_id);
+        let names: Vec<String> = backlog.unwrap().into_iter().map(|evt| evt.name).collect();
+        assert_eq!(names, vec![String::from("b"), String::from("c")]);
+
+        let (backlog, _) = hooks.subscribe_since(first_id + 2);
+        assert_eq!(backlog, Some(vec![]));
+        assert_eq!(hooks.subscribe_since(first_id + 3).0, None);
+        assert_eq!(hooks.subscribe_since(first_id - 2).0, None);
+        assert_eq!(hooks.subscribe_since(first_id - 1).0.unwrap().len(), 3);
+    }
+
+    #[test]
+    fn signs_body() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"key");
+        assert_eq!(
+            sign_body(&key, b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+}
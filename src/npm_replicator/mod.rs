@@ -3,3 +3,8 @@ pub mod types;
 pub mod replication_task;
 pub mod error;
 pub mod registry;
+pub mod retry_queue;
+pub mod negative_cache;
+pub mod stats;
+pub mod supervisor;
+pub mod hooks;
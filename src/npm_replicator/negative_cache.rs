@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+
+#[derive(Default, Debug)]
+struct MissingEntry {
+    // Set when the package itself doesn't exist
+    package_expires_at: Option<Instant>,
+    // Versions or ranges that couldn't be found in the package
+    versions: HashMap<String, Instant>,
+}
+
+/// Remembers packages and versions that don't exist for a while,
+/// so typos and squatted names don't turn into a request to npm every time
+#[derive(Debug)]
+pub struct NegativeCache {
+    ttl: Duration,
+    entries: LruCache<String, MissingEntry>,
+}
+
+impl NegativeCache {
+    pub fn new(capacity: usize, ttl: Duration) -> NegativeCache {
+        NegativeCache {
+            ttl,
+            entries: LruCache::new(NonZeroUsize::new(capacity).unwrap()),
+        }
+    }
+
+    pub fn is_missing(&mut self, pkg_name: &str, version: Option<&str>) -> bool {
+        let now = Instant::now();
+        let entry = match self.entries.get(pkg_name) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        if entry
+            .package_expires_at
+            .map(|expires_at| expires_at > now)
+            .unwrap_or(false)
+        {
+            return true;
+        }
+        match version {
+            Some(version) => entry
+                .versions
+                .get(version)
+                .map(|expires_at| *expires_at > now)
+                .unwrap_or(false),
+            None => false,
+        }
+    }
+
+    pub fn mark_missing(&mut self, pkg_name: &str, version: Option<&str>) {
+        let now = Instant::now();
+        let expires_at = now + self.ttl;
+        let entry = self
+            .entries
+            .get_or_insert_mut(String::from(pkg_name), MissingEntry::default);
+        entry.versions.retain(|_, expires_at| *expires_at > now);
+        match version {
+            Some(version) => {
+                entry.versions.insert(String::from(version), expires_at);
+            }
+            None => {
+                entry.package_expires_at = Some(expires_at);
+            }
+        }
+    }
+
+    /// Forgets everything about the package, called whenever a new version of it gets written
+    pub fn invalidate(&mut self, pkg_name: &str) {
+        self.entries.pop(pkg_name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_packages_and_versions() {
+        let mut cache = NegativeCache::new(10, Duration::from_secs(60));
+        cache.mark_missing("reatc", None);
+        cache.mark_missing("react", Some("^99.0.0"));
+
+        assert!(cache.is_missing("reatc", None));
+        assert!(cache.is_missing("reatc", Some("1.0.0")));
+        assert!(cache.is_missing("react", Some("^99.0.0")));
+        assert!(!cache.is_missing("react", Some("^18.0.0")));
+        assert!(!cache.is_missing("react", None));
+
+        cache.invalidate("react");
+        assert!(!cache.is_missing("react", Some("^99.0.0")));
+
+        let mut expired = NegativeCache::new(10, Duration::ZERO);
+        expired.mark_missing("reatc", None);
+        assert!(!expired.is_missing("reatc", None));
+    }
+}
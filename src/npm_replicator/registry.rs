@@ -1,44 +1,106 @@
-use std::{num::NonZeroUsize, path::PathBuf, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
 
-use lru::LruCache;
+use moka::sync::Cache;
 use parking_lot::Mutex;
-use rocksdb::DB;
+use rocksdb::{Direction, IteratorMode, WriteBatch, DB};
+use serde::{de::DeserializeOwned, Serialize};
 
 use crate::{
     app_error::{AppResult, ServerError},
-    npm::package_data::download_pkg_metadata,
-    utils::{msgpack::serialize_msgpack, time::secs_since_epoch},
+    config::{DatabaseConfig, PackageCacheConfig},
+    npm::{
+        package_data::download_pkg_metadata, registries::RegistryConfig,
+        request_stats::DecayedCounter,
+    },
+    utils::{blocking_pool::BlockingPool, msgpack::serialize_msgpack, time::secs_since_epoch},
 };
 
+use super::hooks::ReplicationHooks;
+use super::negative_cache::NegativeCache;
+use super::retry_queue::RetryEntry;
 use super::types::document::MinimalPackageData;
 
+// Package names can't start with a #, so internal keys never collide with them
+// Retry entries are keyed by their zero-padded next attempt, so they're iterated in the order they're due
+const RETRY_PREFIX: &str = "#CDN_RETRY:";
+// Points from the package name to the next attempt of its retry entry
+const RETRY_INDEX_PREFIX: &str = "#CDN_RETRY_INDEX:";
+const DEAD_LETTER_PREFIX: &str = "#CDN_DLQ:";
+const REQUEST_STATS_KEY: &str = "#CDN_REQUEST_STATS";
+
 #[derive(Clone, Debug)]
 pub struct NpmRocksDB {
     pub db_path: PathBuf,
-    db: Arc<Mutex<DB>>,
-    cache: Arc<Mutex<LruCache<String, Arc<MinimalPackageData>>>>,
+    pub registries: Arc<RegistryConfig>,
+    // RocksDB is thread-safe, reads and writes don't need to go through a lock
+    db: Arc<DB>,
+    cache: Cache<String, Arc<MinimalPackageData>>,
+    negative_cache: Arc<Mutex<NegativeCache>>,
+    blocking_pool: BlockingPool,
+    config: PackageCacheConfig,
+    hooks: ReplicationHooks,
 }
 
 impl NpmRocksDB {
-    pub fn new(db_path: &str) -> Self {
-        let db = DB::open_default(db_path).unwrap();
-        let cache = LruCache::new(NonZeroUsize::new(500).unwrap());
+    pub fn new(
+        db_path: &str,
+        registries: RegistryConfig,
+        config: PackageCacheConfig,
+        db_config: DatabaseConfig,
+    ) -> AppResult<Self> {
+        let db = DB::open_default(db_path)?;
+        let cache = Cache::new(config.min_capacity);
+        let negative_cache = NegativeCache::new(
+            config.missing_capacity,
+            Duration::from_secs(config.missing_ttl),
+        );
 
-        Self {
+        Ok(Self {
             db_path: PathBuf::from(db_path),
-            db: Arc::new(Mutex::new(db)),
-            cache: Arc::new(Mutex::new(cache)),
+            registries: Arc::new(registries),
+            db: Arc::new(db),
+            cache,
+            negative_cache: Arc::new(Mutex::new(negative_cache)),
+            blocking_pool: BlockingPool::new("npm-db-blocking", db_config.blocking_threads),
+            config,
+            hooks: ReplicationHooks::new(),
+        })
+    }
+
+    /// Replaces the hooks that get notified of every written or deleted public package,
+    /// has to be called before the db gets cloned
+    pub fn with_hooks(mut self, hooks: ReplicationHooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    pub fn hooks(&self) -> &ReplicationHooks {
+        &self.hooks
+    }
+
+    /// Replaces the package cache, has to be called before the db gets cloned.
+    /// The capacity is kept within the configured bounds
+    pub fn with_cache_capacity(mut self, capacity: u64) -> Self {
+        self.cache = Cache::new(capacity.clamp(self.config.min_capacity, self.config.max_capacity));
+        self
+    }
+
+    /// Loads the given packages into the cache, packages that can't be read are skipped
+    pub fn warm_cache(&self, pkg_names: &[String]) {
+        for pkg_name in pkg_names {
+            if let Err(err) = self.get_package(pkg_name) {
+                println!("Failed to warm cache for {}: {:?}", pkg_name, err);
+            }
         }
     }
 
     #[tracing::instrument(name = "npm_db_get_last_seq", level = "debug", skip(self))]
     pub fn get_last_seq(&self) -> AppResult<i64> {
-        if let Some(result) = self.db.lock().get(b"#CDN_LAST_SYNC").unwrap() {
-            Ok(i64::from_le_bytes(
-                result[..]
-                    .try_into()
-                    .expect("last sync invalid byte length"),
-            ))
+        if let Some(result) = self.db.get(b"#CDN_LAST_SYNC")? {
+            let bytes: [u8; 8] = result[..]
+                .try_into()
+                .map_err(|_| ServerError::InvalidDatabaseValue(String::from("#CDN_LAST_SYNC")))?;
+            Ok(i64::from_le_bytes(bytes))
         } else {
             Ok(0)
         }
@@ -46,16 +108,154 @@ impl NpmRocksDB {
 
     #[tracing::instrument(name = "npm_db_update_last_seq", level = "debug", skip(self))]
     pub fn update_last_seq(&self, next_seq: i64) -> AppResult<usize> {
-        self.db
-            .lock()
-            .put(b"#CDN_LAST_SYNC", next_seq.to_le_bytes())
-            .unwrap();
+        self.db.put(b"#CDN_LAST_SYNC", next_seq.to_le_bytes())?;
         Ok(1)
     }
 
+    fn put_value<T: Serialize>(&self, key: &str, value: &T) -> AppResult<()> {
+        let content = serialize_msgpack(value)?;
+        self.db.put(key.as_bytes(), content)?;
+        Ok(())
+    }
+
+    fn get_value<T: DeserializeOwned>(&self, key: &str) -> AppResult<Option<T>> {
+        match self.db.get(key.as_bytes())? {
+            Some(content) => {
+                let value = rmp_serde::from_slice(&content)
+                    .map_err(|_| ServerError::InvalidDatabaseValue(key.to_string()))?;
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Values with the prefix in key order, stops at the limit or the first value `take_while` rejects
+    fn get_prefixed_values_while<T: DeserializeOwned>(
+        &self,
+        prefix: &str,
+        limit: usize,
+        take_while: impl Fn(&T) -> bool,
+    ) -> AppResult<Vec<T>> {
+        let mut values = Vec::new();
+        let iter = self
+            .db
+            .iterator(IteratorMode::From(prefix.as_bytes(), Direction::Forward));
+        for item in iter {
+            if values.len() >= limit {
+                break;
+            }
+            let (key, content) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            let value = rmp_serde::from_slice(&content).map_err(|_| {
+                ServerError::InvalidDatabaseValue(String::from_utf8_lossy(&key).to_string())
+            })?;
+            if !take_while(&value) {
+                break;
+            }
+            values.push(value);
+        }
+        Ok(values)
+    }
+
+    fn get_prefixed_values<T: DeserializeOwned>(&self, prefix: &str) -> AppResult<Vec<T>> {
+        self.get_prefixed_values_while(prefix, usize::MAX, |_value| true)
+    }
+
+    fn get_retry_key(next_attempt_at: u64, pkg_name: &str) -> String {
+        format!("{}{:020}:{}", RETRY_PREFIX, next_attempt_at, pkg_name)
+    }
+
+    /// Adds deleting the package's retry entry, if it has one, to the batch
+    fn delete_retry_entry(&self, batch: &mut WriteBatch, pkg_name: &str) -> AppResult<()> {
+        let index_key = format!("{}{}", RETRY_INDEX_PREFIX, pkg_name);
+        if let Some(next_attempt_at) = self.get_value::<u64>(&index_key)? {
+            batch.delete(NpmRocksDB::get_retry_key(next_attempt_at, pkg_name).as_bytes());
+            batch.delete(index_key.as_bytes());
+        }
+        Ok(())
+    }
+
+    /// Every retry entry, the ones that are due first
+    pub fn get_retry_entries(&self) -> AppResult<Vec<RetryEntry>> {
+        self.get_prefixed_values(RETRY_PREFIX)
+    }
+
+    /// Up to `limit` retry entries that are due, without going through the ones that aren't
+    pub fn get_due_retry_entries(&self, now: u64, limit: usize) -> AppResult<Vec<RetryEntry>> {
+        self.get_prefixed_values_while(RETRY_PREFIX, limit, |entry: &RetryEntry| entry.is_due(now))
+    }
+
+    pub fn get_retry_entry(&self, pkg_name: &str) -> AppResult<Option<RetryEntry>> {
+        match self.get_value::<u64>(&format!("{}{}", RETRY_INDEX_PREFIX, pkg_name))? {
+            Some(next_attempt_at) => {
+                self.get_value(&NpmRocksDB::get_retry_key(next_attempt_at, pkg_name))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Writes the entry, replacing the package's previous entry
+    pub fn put_retry_entry(&self, entry: &RetryEntry) -> AppResult<()> {
+        let mut batch = WriteBatch::default();
+        self.delete_retry_entry(&mut batch, &entry.pkg_name)?;
+        batch.put(
+            NpmRocksDB::get_retry_key(entry.next_attempt_at, &entry.pkg_name).as_bytes(),
+            serialize_msgpack(entry)?,
+        );
+        batch.put(
+            format!("{}{}", RETRY_INDEX_PREFIX, entry.pkg_name).as_bytes(),
+            serialize_msgpack(&entry.next_attempt_at)?,
+        );
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    pub fn get_request_stats(&self) -> AppResult<Option<HashMap<String, DecayedCounter>>> {
+        self.get_value(REQUEST_STATS_KEY)
+    }
+
+    pub fn put_request_stats(&self, counters: &HashMap<String, DecayedCounter>) -> AppResult<()> {
+        self.put_value(REQUEST_STATS_KEY, counters)
+    }
+
+    pub fn get_dead_letter(&self, pkg_name: &str) -> AppResult<Option<RetryEntry>> {
+        self.get_value(&format!("{}{}", DEAD_LETTER_PREFIX, pkg_name))
+    }
+
+    pub fn get_dead_letters(&self) -> AppResult<Vec<RetryEntry>> {
+        self.get_prefixed_values(DEAD_LETTER_PREFIX)
+    }
+
+    /// Writes the entry to the dead letter queue and removes it from the retry queue
+    pub fn put_dead_letter(&self, entry: &RetryEntry) -> AppResult<()> {
+        let mut batch = WriteBatch::default();
+        batch.put(
+            format!("{}{}", DEAD_LETTER_PREFIX, entry.pkg_name).as_bytes(),
+            serialize_msgpack(entry)?,
+        );
+        self.delete_retry_entry(&mut batch, &entry.pkg_name)?;
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    /// Removes the package from both the retry and dead letter queue, once it got replicated
+    pub fn clear_failed_package(&self, pkg_name: &str) -> AppResult<()> {
+        let mut batch = WriteBatch::default();
+        self.delete_retry_entry(&mut batch, pkg_name)?;
+        batch.delete(format!("{}{}", DEAD_LETTER_PREFIX, pkg_name).as_bytes());
+        self.db.write(batch)?;
+        Ok(())
+    }
+
     #[tracing::instrument(name = "npm_db_delete_package", level = "debug", skip(self))]
     pub fn delete_package(&self, pkg_name: &str) -> AppResult<usize> {
-        self.db.lock().delete(pkg_name.as_bytes()).unwrap();
+        self.db.delete(pkg_name.as_bytes())?;
+        self.cache.invalidate(pkg_name);
+        if !self.registries.is_private(pkg_name) {
+            self.hooks.on_package_deleted(pkg_name);
+        }
         Ok(1)
     }
 
@@ -67,15 +267,16 @@ impl NpmRocksDB {
         }
 
         let pkg_name = pkg.name.clone();
+        let latest = pkg.dist_tags.get("latest").cloned();
         let content = serialize_msgpack(&pkg)?;
 
-        {
-            self.db.lock().put(pkg_name.as_bytes(), content).unwrap();
-        }
+        self.db.put(pkg_name.as_bytes(), content)?;
+        self.cache.invalidate(&pkg_name);
 
-        {
-            let mut cache = self.cache.lock();
-            cache.pop(&pkg_name);
+        self.negative_cache.lock().invalidate(&pkg_name);
+        // Events are sent to webhooks and anyone can subscribe to them, so private packages are left out
+        if !self.registries.is_private(&pkg_name) {
+            self.hooks.on_package_written(&pkg_name, latest);
         }
 
         Ok(1)
@@ -83,31 +284,27 @@ impl NpmRocksDB {
 
     #[tracing::instrument(name = "npm_db_get_package", level = "debug", skip(self))]
     pub fn get_package(&self, pkg_name: &str) -> AppResult<Arc<MinimalPackageData>> {
-        {
-            let mut cache = self.cache.lock();
-            let cached_value = cache.get(pkg_name);
-            if let Some(pkg_data) = cached_value {
-                tracing::debug!("NPM Cache hit");
-                return Ok(pkg_data.clone());
-            }
-        };
+        if let Some(pkg_data) = self.cache.get(pkg_name) {
+            tracing::debug!("NPM Cache hit");
+            return Ok(pkg_data);
+        }
 
         let content_val: Option<Vec<u8>> = {
             let span = tracing::span!(tracing::Level::DEBUG, "db_get_pkg").entered();
-            let result = self.db.lock().get(pkg_name.as_bytes()).unwrap();
+            let result = self.db.get(pkg_name.as_bytes())?;
             span.exit();
             result
         };
 
         if let Some(pkg_content) = content_val {
             let span = tracing::span!(tracing::Level::DEBUG, "parse_pkg").entered();
-            let found_pkg: MinimalPackageData = rmp_serde::from_slice(&pkg_content)?;
+            let found_pkg: MinimalPackageData = rmp_serde::from_slice(&pkg_content)
+                .map_err(|_| ServerError::InvalidDatabaseValue(pkg_name.to_string()))?;
             span.exit();
 
             let span = tracing::span!(tracing::Level::DEBUG, "write_cached_pkg").entered();
-            let mut cache = self.cache.lock();
             let wrapped_pkg = Arc::new(found_pkg);
-            cache.put(pkg_name.to_string(), wrapped_pkg.clone());
+            self.cache.insert(pkg_name.to_string(), wrapped_pkg.clone());
             span.exit();
 
             Ok(wrapped_pkg)
@@ -118,37 +315,141 @@ impl NpmRocksDB {
         }
     }
 
-    pub async fn fetch_missing_pkg(&mut self, pkg_name: &str) -> Result<(), ServerError> {
-        let mut should_fetch = false;
-        match self.get_package(pkg_name) {
-            Ok(pkg) => {
-                if pkg.last_updated.is_none() {
-                    should_fetch = true;
-                } else {
-                    let last_updated = pkg.last_updated.unwrap();
-                    let now = secs_since_epoch();
-                    let diff = now - last_updated;
-                    if diff > 60 {
-                        should_fetch = true;
-                    }
+    /// Runs blocking work, like resolving a dependency tree, on the db's own blocking pool
+    pub async fn spawn_blocking<F, T>(&self, func: F) -> AppResult<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.blocking_pool.run(func).await
+    }
+
+    pub async fn get_package_async(&self, pkg_name: &str) -> AppResult<Arc<MinimalPackageData>> {
+        // Cache hits never touch the disk, so there's no need to offload those
+        if let Some(pkg_data) = self.cache.get(pkg_name) {
+            return Ok(pkg_data);
+        }
+
+        let db = self.clone();
+        let pkg_name = pkg_name.to_string();
+        self.spawn_blocking(move || db.get_package(&pkg_name))
+            .await?
+    }
+
+    pub async fn write_package_async(&self, pkg: MinimalPackageData) -> AppResult<usize> {
+        let db = self.clone();
+        self.spawn_blocking(move || db.write_package(pkg)).await?
+    }
+
+    pub async fn delete_package_async(&self, pkg_name: &str) -> AppResult<usize> {
+        let db = self.clone();
+        let pkg_name = pkg_name.to_string();
+        self.spawn_blocking(move || db.delete_package(&pkg_name))
+            .await?
+    }
+
+    /// Whether the package, or the version or range of it, was recently found to not exist
+    pub fn is_known_missing(&self, pkg_name: &str, version: Option<&str>) -> bool {
+        self.negative_cache.lock().is_missing(pkg_name, version)
+    }
+
+    pub fn mark_missing(&self, pkg_name: &str, version: Option<&str>) {
+        self.negative_cache.lock().mark_missing(pkg_name, version);
+    }
+
+    pub async fn fetch_missing_pkg(&self, pkg_name: &str) -> Result<(), ServerError> {
+        if self.is_known_missing(pkg_name, None) {
+            return Err(ServerError::PackageNotFound(pkg_name.to_string()));
+        }
+
+        let should_fetch = match self.get_package_async(pkg_name).await {
+            // The clock can go backwards, so don't assume now is always later
+            Ok(pkg) => match pkg.last_updated {
+                Some(last_updated) => {
+                    secs_since_epoch().saturating_sub(last_updated)
+                        > self.config.missing_package_refetch_interval
                 }
+                None => true,
+            },
+            Err(ServerError::PackageNotFound(_)) => true,
+            Err(err) => {
+                return Err(err);
             }
-            Err(err) => match err {
-                ServerError::PackageNotFound(_) => {
-                    should_fetch = true;
+        };
+
+        if should_fetch {
+            let registry = self.registries.get_registry(pkg_name);
+            let metadata = match download_pkg_metadata(pkg_name, registry).await {
+                Ok(metadata) => metadata,
+                Err(ServerError::PackageMetadataDownloadError {
+                    status_code: 404, ..
+                }) => {
+                    self.mark_missing(pkg_name, None);
+                    return Err(ServerError::PackageNotFound(pkg_name.to_string()));
                 }
-                _ => {
+                Err(err) => {
                     return Err(err);
                 }
-            },
-        }
-
-        if should_fetch {
-            let metadata = download_pkg_metadata(pkg_name).await?;
+            };
             let pkg = MinimalPackageData::from_registry_meta(metadata);
-            self.write_package(pkg)?;
+            self.write_package_async(pkg).await?;
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::npm_replicator::hooks::PackageEventKind;
+    use crate::utils::test_utils::{create_test_db, TestPackage, TestRegistry};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn fetched_packages_publish_events() {
+        let registry = TestRegistry::spawn(vec![TestPackage {
+            name: "react",
+            version: "18.2.0",
+            dependencies: vec![],
+            files: vec![],
+        }]);
+        let db = create_test_db(registry.get_registries());
+        let mut receiver = db.hooks().subscribe();
+
+        db.fetch_missing_pkg("react").await.unwrap();
+        let event = receiver.try_recv().unwrap();
+        assert_eq!(event.kind, PackageEventKind::Published);
+        assert_eq!(event.name, "react");
+        assert_eq!(event.latest, Some(String::from("18.2.0")));
+    }
+
+    #[test]
+    fn due_retry_entries() {
+        let db = create_test_db(Default::default());
+        for (pkg_name, next_attempt_at) in [("react", 300), ("vue", 100), ("svelte", 200)] {
+            let mut entry = RetryEntry::new(pkg_name, 0);
+            entry.next_attempt_at = next_attempt_at;
+            db.put_retry_entry(&entry).unwrap();
+        }
+        let get_due = |now: u64, limit: usize| -> Vec<String> {
+            db.get_due_retry_entries(now, limit)
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.pkg_name)
+                .collect()
+        };
+        assert_eq!(get_due(250, 10), vec!["vue", "svelte"]);
+        assert_eq!(get_due(1000, 1), vec!["vue"]);
+
+        // Rescheduling replaces the previous entry
+        let mut entry = db.get_retry_entry("vue").unwrap().unwrap();
+        entry.next_attempt_at = 400;
+        db.put_retry_entry(&entry).unwrap();
+        assert_eq!(get_due(1000, 10), vec!["svelte", "react", "vue"]);
+
+        db.clear_failed_package("svelte").unwrap();
+        assert_eq!(db.get_retry_entry("svelte").unwrap(), None);
+        assert_eq!(db.get_retry_entries().unwrap().len(), 2);
+    }
+}
@@ -1,49 +1,225 @@
 use super::registry::NpmRocksDB;
-use crate::app_error::AppResult;
+use super::retry_queue::RetryEntry;
+use super::supervisor::ReplicationSupervisor;
+use crate::app_error::{AppResult, ServerError};
 use crate::npm::package_data::download_pkg_metadata;
 use crate::npm_replicator::changes::ChangesStream;
 use crate::npm_replicator::types::changes::Event::Change;
 use crate::npm_replicator::types::document::MinimalPackageData;
+use crate::utils::time::secs_since_epoch;
 
 use std::time::Duration;
-use tokio::time::sleep;
+use tokio::time::{sleep, Instant};
 
 const FINISHED_DEBOUNCE: u64 = 60000;
+const UPDATE_SEQ_INTERVAL: Duration = Duration::from_secs(60);
+// Amount of queued retries processed after every page of changes
+const RETRIES_PER_PAGE: usize = 10;
+const DEAD_LETTER_INTERVAL: Duration = Duration::from_secs(3600);
 
-async fn sync(db: NpmRocksDB) -> AppResult<()> {
+enum FetchResult {
+    Written,
+    Deleted,
+    // A temporary failure like a timeout or a 5xx, the package should be retried later
+    Failed(ServerError),
+}
+
+/// Fetches the package metadata from npm and writes it,
+/// only a 404 is trusted to mean that the package is gone
+async fn fetch_package(db: &NpmRocksDB, pkg_name: &str) -> AppResult<FetchResult> {
+    println!("[NPM-Replication] Fetching package {} from npm", pkg_name);
+    let registry = db.registries.get_registry(pkg_name);
+    match download_pkg_metadata(pkg_name, registry).await {
+        Ok(metadata) => {
+            let pkg = MinimalPackageData::from_registry_meta(metadata);
+            db.write_package_async(pkg).await?;
+            Ok(FetchResult::Written)
+        }
+        Err(ServerError::PackageMetadataDownloadError {
+            status_code: 404, ..
+        }) => {
+            db.delete_package_async(pkg_name).await?;
+            Ok(FetchResult::Deleted)
+        }
+        Err(err) => Ok(FetchResult::Failed(err)),
+    }
+}
+
+fn record_dead_letter_failure(
+    db: &NpmRocksDB,
+    mut entry: RetryEntry,
+    err: &ServerError,
+) -> AppResult<()> {
+    let now = secs_since_epoch();
+    entry.record_failure(format!("{:?}", err), now);
+    entry.next_attempt_at = now + DEAD_LETTER_INTERVAL.as_secs();
+    db.put_dead_letter(&entry)
+}
+
+fn queue_retry(db: &NpmRocksDB, pkg_name: &str, err: &ServerError) -> AppResult<()> {
+    // Dead letters stay in their queue until the dead letter worker gets them through,
+    // so a package is never in both queues
+    if let Some(entry) = db.get_dead_letter(pkg_name)? {
+        println!(
+            "[NPM-Replication] Failed to fetch dead letter {} again: {:?}",
+            pkg_name, err
+        );
+        return record_dead_letter_failure(db, entry, err);
+    }
+
+    let now = secs_since_epoch();
+    let mut entry = db
+        .get_retry_entry(pkg_name)?
+        .unwrap_or_else(|| RetryEntry::new(pkg_name, now));
+    entry.record_failure(format!("{:?}", err), now);
+    if entry.is_dead() {
+        db.put_dead_letter(&entry)?;
+        println!(
+            "[NPM-Replication] Failed to fetch package {} {} times, moved it to the dead letter queue: {:?}",
+            pkg_name, entry.attempts, err
+        );
+        return Ok(());
+    }
+
+    db.put_retry_entry(&entry)?;
+    println!(
+        "[NPM-Replication] Failed to fetch package {}, retrying in {}s: {:?}",
+        pkg_name,
+        entry.next_attempt_at - now,
+        err
+    );
+    Ok(())
+}
+
+fn handle_fetch_result(
+    db: &NpmRocksDB,
+    supervisor: &ReplicationSupervisor,
+    pkg_name: &str,
+    result: FetchResult,
+) -> AppResult<()> {
+    match result {
+        FetchResult::Written => {
+            db.clear_failed_package(pkg_name)?;
+            supervisor.stats().record_written();
+            println!("[NPM-Replication] Wrote package {} to db", pkg_name);
+        }
+        FetchResult::Deleted => {
+            db.clear_failed_package(pkg_name)?;
+            supervisor.stats().record_deleted();
+            println!(
+                "[NPM-Replication] Package {} does not exist anymore, removed it",
+                pkg_name
+            );
+        }
+        FetchResult::Failed(err) => {
+            queue_retry(db, pkg_name, &err)?;
+        }
+    }
+    Ok(())
+}
+
+/// Retries packages that failed to fetch before, the longest waiting ones first
+async fn process_retries(db: &NpmRocksDB, supervisor: &ReplicationSupervisor) -> AppResult<()> {
+    let due_entries = db.get_due_retry_entries(secs_since_epoch(), RETRIES_PER_PAGE)?;
+    for entry in due_entries {
+        let result = fetch_package(db, &entry.pkg_name).await?;
+        handle_fetch_result(db, supervisor, &entry.pkg_name, result)?;
+    }
+    Ok(())
+}
+
+/// Retries every dead letter, these have been failing for hours so once in a while is plenty.
+/// One package failing doesn't keep the ones after it from being retried
+async fn retry_dead_letters(db: &NpmRocksDB, supervisor: &ReplicationSupervisor) -> AppResult<()> {
+    for entry in db.get_dead_letters()? {
+        let pkg_name = entry.pkg_name.clone();
+        let result = match fetch_package(db, &pkg_name).await {
+            Ok(FetchResult::Failed(err)) => record_dead_letter_failure(db, entry, &err),
+            Ok(result) => handle_fetch_result(db, supervisor, &pkg_name, result),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            println!(
+                "[NPM-Replication] Failed to retry dead letter {}: {:?}",
+                pkg_name, err
+            );
+        }
+    }
+    Ok(())
+}
+
+fn spawn_dead_letter_worker(db: NpmRocksDB, supervisor: ReplicationSupervisor) {
+    tokio::task::spawn(async move {
+        loop {
+            sleep(DEAD_LETTER_INTERVAL).await;
+            if let Err(err) = retry_dead_letters(&db, &supervisor).await {
+                println!("[NPM-Replication] Dead letter retry failed {:?}", err);
+            }
+        }
+    });
+}
+
+async fn sync(db: NpmRocksDB, supervisor: ReplicationSupervisor) -> AppResult<()> {
     let last_seq: i64 = db.get_last_seq()?;
     println!("[NPM-Replication] Last synced sequence {}", last_seq);
     let mut stream = ChangesStream::new(50, last_seq.into());
+    let stats = supervisor.stats();
+    let mut update_seq_fetched_at: Option<Instant> = None;
     loop {
+        if update_seq_fetched_at
+            .map(|fetched_at| fetched_at.elapsed() > UPDATE_SEQ_INTERVAL)
+            .unwrap_or(true)
+        {
+            match stream.fetch_update_seq().await {
+                Ok(update_seq) => stats.set_update_seq(update_seq),
+                Err(err) => println!("[NPM-Replication] Failed to fetch update seq {}", err),
+            }
+            update_seq_fetched_at = Some(Instant::now());
+        }
+
         match stream.fetch_next().await {
             Ok(page) => {
                 let result_count = { page.results.len() };
+                let change_count = page
+                    .results
+                    .iter()
+                    .filter(|entry| matches!(entry, Change(_)))
+                    .count();
                 for entry in page.results {
                     if let Change(evt) = entry {
+                        // Someone could publish the same name on npm, that should never overwrite our private package
+                        if db.registries.is_private(&evt.id) {
+                            println!("[NPM-Replication] Skipping private package {}", evt.id);
+                            continue;
+                        }
+
                         if evt.deleted {
-                            db.delete_package(&evt.id)?;
+                            db.delete_package_async(&evt.id).await?;
+                            db.clear_failed_package(&evt.id)?;
+                            stats.record_deleted();
                             println!("[NPM-Replication] Deleted package {}", evt.id);
-                        } else if let Some(doc) = evt.doc {
-                            println!("[NPM-Replication] Fetching package {} from npm", evt.id);
-                            let metadata_result = download_pkg_metadata(&doc.id).await;
-                            match metadata_result {
-                                Ok(metadata) => {
-                                    let pkg: MinimalPackageData =
-                                        MinimalPackageData::from_registry_meta(metadata);
-                                    db.write_package(pkg)?;
-                                    println!("[NPM-Replication] Wrote package {} to db", evt.id);
-                                }
-                                Err(_err) => {
-                                    db.delete_package(&evt.id)?;
-                                    println!("[NPM-Replication] Package {} does not seem to exist, removing it", evt.id);
-                                }
-                            }
+                            continue;
                         }
+
+                        let result =
+                            match evt.doc.and_then(MinimalPackageData::from_registry_document) {
+                                Some(pkg) => {
+                                    db.write_package_async(pkg).await?;
+                                    FetchResult::Written
+                                }
+                                // The included doc is missing or couldn't be parsed
+                                None => fetch_package(&db, &evt.id).await?,
+                            };
+                        handle_fetch_result(&db, &supervisor, &evt.id, result)?;
                     }
                 }
 
                 println!("[NPM-Replication] Updated last seq to {}", page.last_seq);
                 db.update_last_seq(page.last_seq)?;
+                supervisor.record_progress();
+                stats.record_changes(change_count as u64);
+
+                process_retries(&db, &supervisor).await?;
 
                 if stream.should_wait(result_count) {
                     sleep(Duration::from_millis(FINISHED_DEBOUNCE)).await;
@@ -51,18 +227,17 @@ async fn sync(db: NpmRocksDB) -> AppResult<()> {
             }
             Err(err) => {
                 println!("NPM Registry sync error {:?}", err);
+                stats.record_fetch_error(format!("{}", err));
                 sleep(Duration::from_millis(FINISHED_DEBOUNCE)).await;
             }
         }
     }
 }
 
-pub fn spawn_sync_thread(db: NpmRocksDB) {
+pub fn spawn_sync_thread(db: NpmRocksDB) -> AppResult<ReplicationSupervisor> {
     println!("[NPM-Replication] Spawning npm sync worker...");
-    tokio::task::spawn(async move {
-        if let Err(err) = sync(db).await {
-            println!("[NPM-Replication] SYNC WORKER CRASHED {:?}", err);
-            sleep(Duration::from_millis(500)).await;
-        }
-    });
+    let supervisor = ReplicationSupervisor::from_env()?;
+    spawn_dead_letter_worker(db.clone(), supervisor.clone());
+    supervisor.spawn(db, sync);
+    Ok(supervisor)
 }
@@ -0,0 +1,78 @@
+use serde::{Deserialize, Serialize};
+
+const MIN_RETRY_DELAY: u64 = 30;
+const MAX_RETRY_DELAY: u64 = 3600;
+// After this many failed attempts the entry is moved to the dead letter queue
+pub const MAX_RETRY_ATTEMPTS: u32 = 8;
+
+/// A package that couldn't be fetched from npm because of a temporary failure,
+/// persisted so the change isn't lost when the replication moves on
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct RetryEntry {
+    pub pkg_name: String,
+    pub attempts: u32,
+    // Timestamps are in seconds since epoch
+    pub next_attempt_at: u64,
+    #[serde(default)]
+    pub first_failed_at: u64,
+    #[serde(default)]
+    pub last_failed_at: u64,
+    #[serde(default)]
+    pub last_error: Option<String>,
+}
+
+impl RetryEntry {
+    pub fn new(pkg_name: &str, now: u64) -> RetryEntry {
+        RetryEntry {
+            pkg_name: String::from(pkg_name),
+            attempts: 0,
+            next_attempt_at: now,
+            first_failed_at: now,
+            last_failed_at: now,
+            last_error: None,
+        }
+    }
+
+    pub fn is_due(&self, now: u64) -> bool {
+        self.next_attempt_at <= now
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.attempts >= MAX_RETRY_ATTEMPTS
+    }
+
+    /// Doubles the delay on every failed attempt, up to an hour
+    pub fn record_failure(&mut self, error: String, now: u64) {
+        self.attempts += 1;
+        self.last_failed_at = now;
+        self.last_error = Some(error);
+        let delay = MIN_RETRY_DELAY.saturating_mul(1 << u32::min(self.attempts - 1, 16));
+        self.next_attempt_at = now + u64::min(delay, MAX_RETRY_DELAY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_backoff() {
+        let mut entry = RetryEntry::new("react", 1000);
+        assert!(entry.is_due(1000));
+
+        entry.record_failure(String::from("timeout"), 1000);
+        assert_eq!(entry.next_attempt_at, 1030);
+        assert_eq!(entry.last_error, Some(String::from("timeout")));
+        assert!(!entry.is_due(1000));
+
+        entry.record_failure(String::from("timeout"), 1000);
+        assert_eq!(entry.next_attempt_at, 1060);
+        assert!(!entry.is_dead());
+
+        for _ in 0..20 {
+            entry.record_failure(String::from("timeout"), 1000);
+        }
+        assert_eq!(entry.next_attempt_at, 1000 + 3600);
+        assert!(entry.is_dead());
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::time::secs_since_epoch;
+
+const BUCKET_SECS: u64 = 10;
+// Enough buckets for the largest window, 15 minutes
+const MAX_BUCKETS: usize = 90;
+
+/// Counts events in buckets of 10 seconds, to calculate the rate over recent windows
+#[derive(Debug, Default)]
+struct ThroughputCounter {
+    // (bucket start in secs since epoch, count), oldest first
+    buckets: VecDeque<(u64, u64)>,
+}
+
+impl ThroughputCounter {
+    fn add(&mut self, now: u64, count: u64) {
+        let bucket_start = now - now % BUCKET_SECS;
+        match self.buckets.back_mut() {
+            Some((start, bucket_count)) if *start == bucket_start => {
+                *bucket_count += count;
+            }
+            _ => {
+                self.buckets.push_back((bucket_start, count));
+                while self.buckets.len() > MAX_BUCKETS {
+                    self.buckets.pop_front();
+                }
+            }
+        }
+    }
+
+    fn get_rate(&self, now: u64, window_secs: u64) -> f64 {
+        let window_start = now.saturating_sub(window_secs);
+        let total: u64 = self
+            .buckets
+            .iter()
+            .filter(|(start, _)| *start >= window_start)
+            .map(|(_, count)| count)
+            .sum();
+        total as f64 / window_secs as f64
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ChangesPerSecond {
+    pub last_1m: f64,
+    pub last_5m: f64,
+    pub last_15m: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct FetchError {
+    pub message: String,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ReplicationStatsSnapshot {
+    // Latest sequence of the npm registry, refreshed periodically
+    pub update_seq: Option<i64>,
+    pub last_change_at: Option<u64>,
+    pub changes_per_second: ChangesPerSecond,
+    pub packages_written: u64,
+    pub packages_deleted: u64,
+    pub last_fetch_error: Option<FetchError>,
+}
+
+#[derive(Debug, Default)]
+struct StatsInner {
+    update_seq: Option<i64>,
+    last_change_at: Option<u64>,
+    throughput: ThroughputCounter,
+    packages_written: u64,
+    packages_deleted: u64,
+    last_fetch_error: Option<FetchError>,
+}
+
+/// Counters about the npm replication, since the server started
+#[derive(Clone, Debug, Default)]
+pub struct ReplicationStats {
+    inner: Arc<RwLock<StatsInner>>,
+}
+
+impl ReplicationStats {
+    pub fn set_update_seq(&self, update_seq: i64) {
+        self.inner.write().update_seq = Some(update_seq);
+    }
+
+    pub fn record_changes(&self, count: u64) {
+        if count == 0 {
+            return;
+        }
+        let now = secs_since_epoch();
+        let mut inner = self.inner.write();
+        inner.throughput.add(now, count);
+        inner.last_change_at = Some(now);
+    }
+
+    pub fn record_written(&self) {
+        self.inner.write().packages_written += 1;
+    }
+
+    pub fn record_deleted(&self) {
+        self.inner.write().packages_deleted += 1;
+    }
+
+    pub fn record_fetch_error(&self, message: String) {
+        self.inner.write().last_fetch_error = Some(FetchError {
+            message,
+            timestamp: secs_since_epoch(),
+        });
+    }
+
+    pub fn get_snapshot(&self) -> ReplicationStatsSnapshot {
+        let now = secs_since_epoch();
+        let inner = self.inner.read();
+        ReplicationStatsSnapshot {
+            update_seq: inner.update_seq,
+            last_change_at: inner.last_change_at,
+            changes_per_second: ChangesPerSecond {
+                last_1m: inner.throughput.get_rate(now, 60),
+                last_5m: inner.throughput.get_rate(now, 300),
+                last_15m: inner.throughput.get_rate(now, 900),
+            },
+            packages_written: inner.packages_written,
+            packages_deleted: inner.packages_deleted,
+            last_fetch_error: inner.last_fetch_error.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throughput_windows() {
+        let mut counter = ThroughputCounter::default();
+        let now = 10_000;
+        counter.add(now - 600, 300);
+        counter.add(now - 30, 50);
+        counter.add(now - 25, 10);
+
+        assert_eq!(counter.get_rate(now, 60), 1.0);
+        assert_eq!(counter.get_rate(now, 900), 0.4);
+    }
+}
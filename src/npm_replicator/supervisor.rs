@@ -0,0 +1,182 @@
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, Instant};
+
+use crate::app_error::{AppResult, ServerError};
+use crate::utils::time::secs_since_epoch;
+
+use super::registry::NpmRocksDB;
+use super::stats::ReplicationStats;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+// A run that lasted this long was healthy, so the next failure starts backing off from scratch
+const HEALTHY_RUN_DURATION: Duration = Duration::from_secs(600);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SupervisorState {
+    Running,
+    // The sync loop returned an error and is waiting to be restarted
+    BackingOff,
+    // The sync loop panicked and is waiting to be restarted
+    Crashed,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct SupervisorStatus {
+    pub state: SupervisorState,
+    pub last_error: Option<String>,
+    pub restart_count: u64,
+    // Seconds since epoch of the last successfully processed page of changes
+    pub last_progress_at: u64,
+    pub is_stuck: bool,
+}
+
+#[derive(Debug)]
+struct SupervisorInner {
+    state: SupervisorState,
+    last_error: Option<String>,
+    restart_count: u64,
+    last_progress_at: u64,
+}
+
+/// Keeps the npm replication running, restarting it with exponential backoff whenever it fails
+#[derive(Clone, Debug)]
+pub struct ReplicationSupervisor {
+    // Replication is considered stuck when it hasn't made progress for this long
+    stuck_threshold: Duration,
+    inner: Arc<RwLock<SupervisorInner>>,
+    stats: ReplicationStats,
+}
+
+fn get_panic_message(err: tokio::task::JoinError) -> String {
+    match err.try_into_panic() {
+        Ok(panic) => match panic.downcast_ref::<&str>() {
+            Some(message) => format!("Panicked: {}", message),
+            None => match panic.downcast_ref::<String>() {
+                Some(message) => format!("Panicked: {}", message),
+                None => String::from("Panicked"),
+            },
+        },
+        Err(err) => format!("{}", err),
+    }
+}
+
+impl ReplicationSupervisor {
+    pub fn new(stuck_threshold: Duration) -> ReplicationSupervisor {
+        ReplicationSupervisor {
+            stuck_threshold,
+            inner: Arc::new(RwLock::new(SupervisorInner {
+                state: SupervisorState::Running,
+                last_error: None,
+                restart_count: 0,
+                // Give the first run the full threshold before calling it stuck
+                last_progress_at: secs_since_epoch(),
+            })),
+            stats: ReplicationStats::default(),
+        }
+    }
+
+    /// Reads the stuck threshold in seconds from REPLICATION_STUCK_THRESHOLD, defaults to 15 minutes
+    pub fn from_env() -> AppResult<ReplicationSupervisor> {
+        let stuck_threshold = match env::var("REPLICATION_STUCK_THRESHOLD") {
+            Ok(value) => value.parse().map_err(|_| {
+                ServerError::InvalidConfig(String::from(
+                    "REPLICATION_STUCK_THRESHOLD should be a number of seconds",
+                ))
+            })?,
+            Err(_) => 900,
+        };
+        Ok(ReplicationSupervisor::new(Duration::from_secs(
+            stuck_threshold,
+        )))
+    }
+
+    pub fn stats(&self) -> &ReplicationStats {
+        &self.stats
+    }
+
+    pub fn record_progress(&self) {
+        self.inner.write().last_progress_at = secs_since_epoch();
+    }
+
+    pub fn is_stuck(&self) -> bool {
+        let last_progress_at = self.inner.read().last_progress_at;
+        secs_since_epoch().saturating_sub(last_progress_at) > self.stuck_threshold.as_secs()
+    }
+
+    pub fn get_status(&self) -> SupervisorStatus {
+        let is_stuck = self.is_stuck();
+        let inner = self.inner.read();
+        SupervisorStatus {
+            state: inner.state,
+            last_error: inner.last_error.clone(),
+            restart_count: inner.restart_count,
+            last_progress_at: inner.last_progress_at,
+            is_stuck,
+        }
+    }
+
+    fn set_failed(&self, state: SupervisorState, error: String) {
+        let mut inner = self.inner.write();
+        inner.state = state;
+        inner.last_error = Some(error);
+    }
+
+    fn set_restarted(&self) {
+        let mut inner = self.inner.write();
+        inner.state = SupervisorState::Running;
+        inner.restart_count += 1;
+    }
+
+    /// Runs the sync loop in its own task, so panics are caught as well
+    pub fn spawn<F, Fut>(&self, db: NpmRocksDB, sync: F)
+    where
+        F: Fn(NpmRocksDB, ReplicationSupervisor) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = AppResult<()>> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        tokio::task::spawn(async move {
+            let mut backoff = MIN_BACKOFF;
+            loop {
+                let started_at = Instant::now();
+                let result = tokio::task::spawn(sync(db.clone(), supervisor.clone())).await;
+                if started_at.elapsed() > HEALTHY_RUN_DURATION {
+                    backoff = MIN_BACKOFF;
+                }
+
+                match result {
+                    Ok(Ok(())) => {
+                        println!("[NPM-Replication] Sync worker stopped, restarting");
+                        supervisor.set_failed(
+                            SupervisorState::BackingOff,
+                            String::from("Sync worker stopped"),
+                        );
+                    }
+                    Ok(Err(err)) => {
+                        println!("[NPM-Replication] Sync worker failed {:?}", err);
+                        supervisor.set_failed(SupervisorState::BackingOff, format!("{:?}", err));
+                    }
+                    Err(err) => {
+                        let message = get_panic_message(err);
+                        println!("[NPM-Replication] SYNC WORKER CRASHED {}", message);
+                        supervisor.set_failed(SupervisorState::Crashed, message);
+                    }
+                }
+
+                println!(
+                    "[NPM-Replication] Restarting sync worker in {}s",
+                    backoff.as_secs()
+                );
+                sleep(backoff).await;
+                backoff = Duration::min(backoff * 2, MAX_BACKOFF);
+                supervisor.set_restarted();
+            }
+        });
+    }
+}
@@ -42,3 +42,9 @@ pub struct ChangesPage {
     pub results: Vec<Event>,
     pub last_seq: i64,
 }
+
+/// The root document of the registry database, only the fields we need
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct DatabaseInfo {
+    pub update_seq: i64,
+}
@@ -18,6 +18,18 @@ pub struct DocumentPackageVersion {
     #[serde(default, rename = "optionalDependencies")]
     #[serde_as(deserialize_as = "DefaultOnError")]
     pub optional_dependencies: Option<BTreeMap<String, String>>,
+    #[serde(default)]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    pub deprecated: Option<String>,
+    #[serde(default)]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    pub engines: Option<BTreeMap<String, String>>,
+    #[serde(default)]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    pub os: Option<Vec<String>>,
+    #[serde(default)]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    pub cpu: Option<Vec<String>>,
     pub dist: DocumentPackageDist,
 }
 
@@ -35,10 +47,20 @@ pub struct RegistryDocument {
     pub versions: Option<BTreeMap<String, DocumentPackageVersion>>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
 pub struct MinimalPackageVersionData {
     pub tarball: String,
     pub dependencies: BTreeMap<String, String>,
+    #[serde(default)]
+    pub optional_dependencies: BTreeMap<String, String>,
+    #[serde(default)]
+    pub deprecated: Option<String>,
+    #[serde(default)]
+    pub engines: BTreeMap<String, String>,
+    #[serde(default)]
+    pub os: Vec<String>,
+    #[serde(default)]
+    pub cpu: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
@@ -64,9 +86,76 @@ impl MinimalPackageData {
                 MinimalPackageVersionData {
                     tarball: value.dist.tarball,
                     dependencies: value.dependencies,
+                    optional_dependencies: value.optional_dependencies,
+                    // npm unpublishes a deprecation by setting it to an empty string
+                    deprecated: value.deprecated.filter(|message| !message.is_empty()),
+                    engines: value.engines,
+                    os: value.os,
+                    cpu: value.cpu,
                 },
             );
         }
         data
     }
+
+    /// Converts the document included in the changes feed,
+    /// returns nothing when it's incomplete and the metadata should be fetched instead
+    pub fn from_registry_document(doc: RegistryDocument) -> Option<MinimalPackageData> {
+        let dist_tags = doc.dist_tags?;
+        let versions = doc.versions?;
+        let mut data = MinimalPackageData {
+            name: doc.id,
+            dist_tags,
+            versions: BTreeMap::new(),
+            last_updated: Some(secs_since_epoch()),
+        };
+        for (key, value) in versions {
+            data.versions.insert(
+                key,
+                MinimalPackageVersionData {
+                    tarball: value.dist.tarball,
+                    dependencies: value.dependencies.unwrap_or_default(),
+                    optional_dependencies: value.optional_dependencies.unwrap_or_default(),
+                    deprecated: value.deprecated.filter(|message| !message.is_empty()),
+                    engines: value.engines.unwrap_or_default(),
+                    os: value.os.unwrap_or_default(),
+                    cpu: value.cpu.unwrap_or_default(),
+                },
+            );
+        }
+        Some(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry_document_conversion() {
+        let doc: RegistryDocument = serde_json::from_str(
+            r#"{
+                "_id": "left-pad",
+                "dist-tags": { "latest": "1.3.0" },
+                "versions": {
+                    "1.3.0": {
+                        "dependencies": "invalid",
+                        "deprecated": "",
+                        "dist": { "tarball": "https://registry.npmjs.org/left-pad/-/left-pad-1.3.0.tgz" }
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        let data = MinimalPackageData::from_registry_document(doc).unwrap();
+        assert_eq!(data.name, "left-pad");
+        assert_eq!(data.dist_tags.get("latest"), Some(&String::from("1.3.0")));
+        let version = data.versions.get("1.3.0").unwrap();
+        assert!(version.dependencies.is_empty());
+        assert_eq!(version.deprecated, None);
+
+        let incomplete: RegistryDocument =
+            serde_json::from_str(r#"{ "_id": "left-pad", "versions": {} }"#).unwrap();
+        assert_eq!(MinimalPackageData::from_registry_document(incomplete), None);
+    }
 }
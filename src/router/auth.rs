@@ -0,0 +1,264 @@
+use std::collections::HashSet;
+use std::{env, fmt, time::Duration};
+
+use moka::future::Cache;
+use serde::{Deserialize, Serialize};
+
+use crate::app_error::ServerError;
+use crate::npm::dep_tree_builder::ResolutionsMap;
+use crate::npm::registries::RegistryConfig;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Principal {
+    Anonymous,
+    // Scopes this token has access to, eg. @acme
+    Scopes(HashSet<String>),
+    All,
+}
+
+impl Principal {
+    fn can_access_scope(&self, scope: &str) -> bool {
+        match self {
+            Principal::Anonymous => false,
+            Principal::Scopes(scopes) => scopes.contains(scope),
+            Principal::All => true,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct IntrospectionRequest<'a> {
+    token: &'a str,
+}
+
+#[derive(Deserialize, Debug)]
+struct IntrospectionResponse {
+    active: bool,
+    #[serde(default)]
+    scopes: Vec<String>,
+}
+
+// Compares in constant time, so the secret can't be guessed by timing requests
+fn secure_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.bytes()
+        .zip(b.bytes())
+        .fold(0, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
+
+fn get_bearer_token(authorization: Option<&str>) -> Option<&str> {
+    authorization
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Validates bearer tokens, either against AUTH_SECRET which grants access to everything
+/// or by posting `{ "token": "..." }` to AUTH_INTROSPECTION_URL
+/// which should respond with `{ "active": true, "scopes": ["@acme"] }`
+#[derive(Clone)]
+pub struct Authenticator {
+    secret: Option<String>,
+    introspection_url: Option<String>,
+    client: reqwest::Client,
+    cache: Cache<String, Principal>,
+    // Tokens that couldn't be introspected are treated as anonymous for a little while,
+    // so sending the same bad token doesn't cost a request to the introspection url every time
+    failed_tokens: Cache<String, ()>,
+}
+
+impl Authenticator {
+    pub fn new(secret: Option<String>, introspection_url: Option<String>) -> Authenticator {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("reqwest::ClientBuilder::build()");
+        Authenticator {
+            secret,
+            introspection_url,
+            client,
+            cache: Cache::builder()
+                .max_capacity(10000)
+                .time_to_live(Duration::from_secs(60))
+                .build(),
+            failed_tokens: Cache::builder()
+                .max_capacity(10000)
+                .time_to_live(Duration::from_secs(10))
+                .build(),
+        }
+    }
+
+    pub fn from_env() -> Authenticator {
+        Authenticator::new(
+            env::var("AUTH_SECRET").ok(),
+            env::var("AUTH_INTROSPECTION_URL").ok(),
+        )
+    }
+
+    async fn introspect(&self, url: &str, token: &str) -> Result<Principal, ServerError> {
+        let response = self
+            .client
+            .post(url)
+            .json(&IntrospectionRequest { token })
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(ServerError::RequestErrorStatus {
+                status_code: response.status().as_u16(),
+            });
+        }
+
+        let introspection: IntrospectionResponse = response.json().await?;
+        if !introspection.active {
+            return Ok(Principal::Anonymous);
+        }
+        if introspection.scopes.iter().any(|scope| scope == "*") {
+            return Ok(Principal::All);
+        }
+        Ok(Principal::Scopes(
+            introspection.scopes.into_iter().collect(),
+        ))
+    }
+
+    /// The principal for the authorization header when it's known without introspecting the token
+    pub async fn get_known_principal(&self, authorization: Option<&str>) -> Option<Principal> {
+        let token = match get_bearer_token(authorization) {
+            Some(token) => token,
+            None => return Some(Principal::Anonymous),
+        };
+
+        if let Some(secret) = &self.secret {
+            if secure_eq(secret, token) {
+                return Some(Principal::All);
+            }
+        }
+
+        if self.introspection_url.is_none() || self.failed_tokens.contains_key(token) {
+            return Some(Principal::Anonymous);
+        }
+        self.cache.get(token).await
+    }
+
+    pub async fn authenticate(&self, authorization: Option<&str>) -> Principal {
+        if let Some(principal) = self.get_known_principal(authorization).await {
+            return principal;
+        }
+
+        let (token, introspection_url) =
+            match (get_bearer_token(authorization), &self.introspection_url) {
+                (Some(token), Some(url)) => (token, url),
+                _ => return Principal::Anonymous,
+            };
+        match self.introspect(introspection_url, token).await {
+            Ok(principal) => {
+                self.cache
+                    .insert(String::from(token), principal.clone())
+                    .await;
+                principal
+            }
+            Err(err) => {
+                tracing::error!("Token introspection failed: {}", err);
+                self.failed_tokens.insert(String::from(token), ()).await;
+                Principal::Anonymous
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Authenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Authenticator")
+    }
+}
+
+/// Checks whether the principal is allowed to access the package,
+/// returns whether the package is private so responses don't end up in shared caches
+pub fn check_access(
+    principal: &Principal,
+    registries: &RegistryConfig,
+    pkg_name: &str,
+) -> Result<bool, ServerError> {
+    if !registries.is_private(pkg_name) {
+        return Ok(false);
+    }
+
+    let scope = pkg_name.split('/').next().unwrap_or(pkg_name);
+    match principal {
+        _ if principal.can_access_scope(scope) => Ok(true),
+        Principal::Anonymous => Err(ServerError::Unauthorized),
+        _ => Err(ServerError::Forbidden(format!("package {}", pkg_name))),
+    }
+}
+
+/// Admin routes are only available with AUTH_SECRET or a token with the `*` scope
+pub fn check_admin_access(principal: &Principal) -> Result<(), ServerError> {
+    match principal {
+        Principal::All => Ok(()),
+        Principal::Anonymous => Err(ServerError::Unauthorized),
+        Principal::Scopes(_) => Err(ServerError::Forbidden(String::from("admin routes"))),
+    }
+}
+
+pub fn check_resolutions_access(
+    principal: &Principal,
+    registries: &RegistryConfig,
+    resolutions: &ResolutionsMap,
+) -> Result<bool, ServerError> {
+    let mut is_private = false;
+    for key in resolutions.keys() {
+        if let Some((pkg_name, _)) = key.rsplit_once('@') {
+            is_private |= check_access(principal, registries, pkg_name)?;
+        }
+    }
+    Ok(is_private)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_package_access() {
+        let registries = RegistryConfig::from_npmrc("@acme:registry=https://npm.acme.com/");
+        let acme = Principal::Scopes(HashSet::from([String::from("@acme")]));
+        let other = Principal::Scopes(HashSet::from([String::from("@other")]));
+
+        assert!(matches!(
+            check_access(&Principal::Anonymous, &registries, "react"),
+            Ok(false)
+        ));
+        assert!(matches!(
+            check_access(&acme, &registries, "@acme/button"),
+            Ok(true)
+        ));
+        assert!(matches!(
+            check_access(&Principal::All, &registries, "@acme/button"),
+            Ok(true)
+        ));
+        assert!(matches!(
+            check_access(&other, &registries, "@acme/button"),
+            Err(ServerError::Forbidden(_))
+        ));
+        assert!(matches!(
+            check_access(&Principal::Anonymous, &registries, "@acme/button"),
+            Err(ServerError::Unauthorized)
+        ));
+    }
+
+    #[test]
+    fn admin_access() {
+        let acme = Principal::Scopes(HashSet::from([String::from("@acme")]));
+
+        assert!(check_admin_access(&Principal::All).is_ok());
+        assert!(matches!(
+            check_admin_access(&acme),
+            Err(ServerError::Forbidden(_))
+        ));
+        assert!(matches!(
+            check_admin_access(&Principal::Anonymous),
+            Err(ServerError::Unauthorized)
+        ));
+    }
+}
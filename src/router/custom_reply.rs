@@ -54,6 +54,26 @@ impl CustomReply {
     pub fn set_status(&mut self, status: StatusCode) {
         self.status = status;
     }
+
+    /// Private responses can only be cached by the browser, never by the CDN
+    pub fn add_cache_headers(&mut self, cache_ttl: u32, is_private: bool) {
+        if is_private {
+            self.add_header(
+                "Cache-Control",
+                format!("private, max-age={}", cache_ttl).as_str(),
+            );
+            self.add_header("CDN-Cache-Control", "no-store");
+        } else {
+            self.add_header(
+                "Cache-Control",
+                format!("public, max-age={}", cache_ttl).as_str(),
+            );
+            self.add_header(
+                "CDN-Cache-Control",
+                format!("max-age={}", cache_ttl).as_str(),
+            );
+        }
+    }
 }
 
 impl Reply for CustomReply {
@@ -5,7 +5,7 @@ use crate::app_error::ServerError;
 
 use super::custom_reply::CustomReply;
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct ErrorReply {
     status: u16,
     message: String,
@@ -24,20 +24,28 @@ impl ErrorReply {
     pub fn as_reply(&self, cache_ttl: u32) -> Result<CustomReply, ServerError> {
         let mut reply = CustomReply::json(self)?;
         reply.set_status(StatusCode::from_u16(self.status)?);
-        reply.add_header(
-            "Cache-Control",
-            format!("public, max-age={}", cache_ttl).as_str(),
-        );
-        reply.add_header(
-            "CDN-Cache-Control",
-            format!("max-age={}", cache_ttl).as_str(),
-        );
+        // Auth and rate limit errors depend on the client, the CDN doesn't vary on that so these should never end up in there
+        let is_client_error = matches!(self.status, 401 | 403 | 429);
+        // Storage failures are usually temporary, so shouldn't stick around in any cache
+        let cache_ttl = match self.status {
+            503 => 0,
+            _ => cache_ttl,
+        };
+        reply.add_cache_headers(cache_ttl, is_client_error);
         Ok(reply)
     }
 }
 
 impl From<ServerError> for ErrorReply {
     fn from(err: ServerError) -> Self {
-        ErrorReply::new(500, format!("{}", err), format!("{:?}", err))
+        let status = match err {
+            ServerError::InvalidQuery => 400,
+            ServerError::Unauthorized => 401,
+            ServerError::Forbidden(_) => 403,
+            ServerError::TooManyRequests { .. } => 429,
+            ServerError::Database(_) | ServerError::InvalidDatabaseValue(_) => 503,
+            _ => 500,
+        };
+        ErrorReply::new(status, format!("{}", err), format!("{:?}", err))
     }
 }
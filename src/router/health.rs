@@ -1,11 +1,33 @@
+use serde::Serialize;
 use warp::{Filter, Rejection, Reply};
 
-pub async fn health_route_handler() -> Result<impl Reply, Rejection> {
-    Ok("ok")
+use crate::npm_replicator::supervisor::ReplicationSupervisor;
+
+use super::routes::with_data;
+
+#[derive(Serialize, Debug)]
+struct HealthStatus {
+    status: &'static str,
+    replication_stuck: bool,
+}
+
+pub async fn health_route_handler(
+    replication_supervisor: ReplicationSupervisor,
+) -> Result<impl Reply, Rejection> {
+    // The server can still serve everything it has while replication is stuck, only the data gets outdated,
+    // so this is reported as degraded instead of failing the health check and getting the instance restarted
+    let replication_stuck = replication_supervisor.is_stuck();
+    Ok(warp::reply::json(&HealthStatus {
+        status: if replication_stuck { "degraded" } else { "ok" },
+        replication_stuck,
+    }))
 }
 
-pub fn health_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+pub fn health_route(
+    replication_supervisor: ReplicationSupervisor,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("health")
         .and(warp::get())
+        .and(with_data(replication_supervisor))
         .and_then(health_route_handler)
 }
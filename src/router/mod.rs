@@ -1,6 +1,8 @@
 pub mod routes;
+mod auth;
 mod custom_reply;
 mod error_reply;
 mod health;
+mod rate_limit;
 mod utils;
 mod routes_v2;
@@ -0,0 +1,318 @@
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use moka::future::Cache;
+use parking_lot::Mutex;
+use warp::filters::BoxedFilter;
+use warp::http::HeaderMap;
+use warp::path::FullPath;
+use warp::{Filter, Rejection};
+
+use crate::app_error::{AppResult, ServerError};
+use crate::utils::token_bucket::{TokenBucket, TokenBucketConfig};
+
+use super::auth::{Authenticator, Principal};
+use super::custom_reply::CustomReply;
+use super::error_reply::ErrorReply;
+use super::routes::with_data;
+
+pub type PrincipalFilter = BoxedFilter<(Principal,)>;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum RouteClass {
+    // Responses that never fetch from the npm registry, like the sync status and admin routes
+    Cached,
+    // Responses that resolve dependencies or can end up fetching from the npm registry
+    Upstream,
+}
+
+impl RouteClass {
+    fn from_path(path: &str) -> Option<RouteClass> {
+        // Modules and manifests are downloaded from npm when they aren't cached or replicated yet
+        const UPSTREAM_PREFIXES: [&str; 8] = [
+            "/v2/deps/",
+            "/v2/json/deps/",
+            "/v2/bundle/",
+            "/v2/types/",
+            "/v2/mod/",
+            "/v2/mods/",
+            "/v2/pkg/",
+            "/v2/json/pkg/",
+        ];
+
+        if path == "/health" {
+            return None;
+        }
+        if UPSTREAM_PREFIXES
+            .iter()
+            .any(|prefix| path.starts_with(prefix))
+        {
+            Some(RouteClass::Upstream)
+        } else {
+            Some(RouteClass::Cached)
+        }
+    }
+}
+
+fn env_f64(name: &str, default: f64) -> AppResult<f64> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| ServerError::InvalidConfig(format!("{} should be a number", name))),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Token bucket per client and route class, clients are identified by their
+/// authorization token when it's valid and by their ip address otherwise
+#[derive(Clone)]
+pub struct RateLimiter {
+    cached: TokenBucketConfig,
+    upstream: TokenBucketConfig,
+    // Header containing the client ip when running behind a proxy, eg. cf-connecting-ip
+    ip_header: Option<String>,
+    buckets: Cache<(String, RouteClass), Arc<Mutex<TokenBucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(
+        cached: TokenBucketConfig,
+        upstream: TokenBucketConfig,
+        ip_header: Option<String>,
+    ) -> RateLimiter {
+        RateLimiter {
+            cached,
+            upstream,
+            ip_header,
+            // Idle buckets have been refilled long before they expire
+            buckets: Cache::builder()
+                .max_capacity(100_000)
+                .time_to_idle(Duration::from_secs(600))
+                .build(),
+        }
+    }
+
+    pub fn from_env() -> AppResult<RateLimiter> {
+        Ok(RateLimiter::new(
+            TokenBucketConfig {
+                burst: env_f64("RATE_LIMIT_CACHED_BURST", 1000.0)?,
+                refill_rate: env_f64("RATE_LIMIT_CACHED_RATE", 100.0)?,
+            },
+            TokenBucketConfig {
+                burst: env_f64("RATE_LIMIT_UPSTREAM_BURST", 50.0)?,
+                refill_rate: env_f64("RATE_LIMIT_UPSTREAM_RATE", 5.0)?,
+            },
+            env::var("RATE_LIMIT_IP_HEADER").ok(),
+        ))
+    }
+
+    fn get_client_key(
+        &self,
+        headers: &HeaderMap,
+        remote: Option<SocketAddr>,
+        principal: &Principal,
+    ) -> String {
+        // Only valid tokens get their own budget, otherwise rotating random tokens would bypass the limit
+        if *principal != Principal::Anonymous {
+            if let Some(authorization) = headers
+                .get("authorization")
+                .and_then(|value| value.to_str().ok())
+            {
+                return format!("token:{}", authorization);
+            }
+        }
+        self.get_ip_key(headers, remote)
+    }
+
+    fn get_ip_key(&self, headers: &HeaderMap, remote: Option<SocketAddr>) -> String {
+        let forwarded_ip = self
+            .ip_header
+            .as_ref()
+            .and_then(|name| headers.get(name.as_str()))
+            .and_then(|value| value.to_str().ok())
+            // x-forwarded-for is a list of ips, the first one is the client
+            .and_then(|value| value.split(',').next())
+            .map(|ip| ip.trim().to_string());
+        match (forwarded_ip, remote) {
+            (Some(ip), _) => format!("ip:{}", ip),
+            (None, Some(addr)) => format!("ip:{}", addr.ip()),
+            (None, None) => String::from("ip:unknown"),
+        }
+    }
+
+    pub async fn check(&self, client_key: String, class: RouteClass) -> Result<(), ServerError> {
+        let config = match class {
+            RouteClass::Cached => self.cached,
+            RouteClass::Upstream => self.upstream,
+        };
+        let bucket = self
+            .buckets
+            .get_with((client_key, class), async move {
+                Arc::new(Mutex::new(TokenBucket::new(config, Instant::now())))
+            })
+            .await;
+
+        let result = bucket.lock().try_take(Instant::now());
+        result.map_err(|wait| ServerError::TooManyRequests {
+            retry_after: u64::max(1, wait.as_secs_f64().ceil() as u64),
+        })
+    }
+}
+
+/// Authenticates the request and rate limits it, routes add this after matching their path
+/// so every request is only authenticated and counted once.
+/// Tokens that have to be introspected are charged to the ip first, so random tokens can't flood the introspection url
+pub fn with_principal(limiter: RateLimiter, authenticator: Authenticator) -> PrincipalFilter {
+    warp::path::full()
+        .and(warp::header::headers_cloned())
+        .and(warp::addr::remote())
+        .and(with_data(limiter))
+        .and(with_data(authenticator))
+        .and_then(
+            |path: FullPath,
+             headers: HeaderMap,
+             remote: Option<SocketAddr>,
+             limiter: RateLimiter,
+             authenticator: Authenticator| async move {
+                let class = RouteClass::from_path(path.as_str());
+                let authorization = headers
+                    .get("authorization")
+                    .and_then(|value| value.to_str().ok());
+                let mut is_ip_charged = false;
+                let principal = match authenticator.get_known_principal(authorization).await {
+                    Some(principal) => principal,
+                    None => {
+                        if let Some(class) = class {
+                            limiter
+                                .check(limiter.get_ip_key(&headers, remote), class)
+                                .await
+                                .map_err(warp::reject::custom)?;
+                            is_ip_charged = true;
+                        }
+                        authenticator.authenticate(authorization).await
+                    }
+                };
+
+                if let Some(class) = class {
+                    // Invalid tokens fall back to the ip, which has been charged already
+                    if !is_ip_charged || principal != Principal::Anonymous {
+                        let client_key = limiter.get_client_key(&headers, remote, &principal);
+                        limiter
+                            .check(client_key, class)
+                            .await
+                            .map_err(warp::reject::custom)?;
+                    }
+                }
+                Ok::<_, Rejection>(principal)
+            },
+        )
+        .boxed()
+}
+
+/// Rate limits routes that don't need the principal
+pub fn rate_limit(
+    principal: PrincipalFilter,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    principal.map(|_principal: Principal| ()).untuple_one()
+}
+
+pub fn get_rate_limit_reply(err: &Rejection) -> Option<CustomReply> {
+    if let Some(ServerError::TooManyRequests { retry_after }) = err.find::<ServerError>() {
+        let retry_after = *retry_after;
+        let mut reply = ErrorReply::from(ServerError::TooManyRequests { retry_after })
+            .as_reply(0)
+            .unwrap();
+        reply.add_header("Retry-After", retry_after.to_string().as_str());
+        return Some(reply);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn route_classes() {
+        for path in [
+            "/v2/mod/a",
+            "/v2/mods/a",
+            "/v2/pkg/a",
+            "/v2/json/pkg/a",
+            "/v2/deps/a",
+        ] {
+            assert_eq!(RouteClass::from_path(path), Some(RouteClass::Upstream));
+        }
+        assert_eq!(
+            RouteClass::from_path("/v2/npm_sync_status"),
+            Some(RouteClass::Cached)
+        );
+        assert_eq!(RouteClass::from_path("/health"), None);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_requests_dont_fall_through() {
+        let config = TokenBucketConfig {
+            burst: 1.0,
+            refill_rate: 0.0,
+        };
+        let principal = with_principal(
+            RateLimiter::new(config, config, None),
+            Authenticator::new(None, None),
+        );
+        let route = warp::path!("v2" / "deps" / String)
+            .and(principal.clone())
+            .map(|_path: String, _principal: Principal| "deps")
+            .or(warp::path!("v2" / "pkg" / String)
+                .and(principal)
+                .map(|_path: String, _principal: Principal| "pkg"));
+
+        let request = || warp::test::request().path("/v2/deps/cmVhY3Q=");
+        assert!(request().filter(&route).await.is_ok());
+        let err = request().filter(&route).await.unwrap_err();
+        let reply = get_rate_limit_reply(&err).unwrap();
+        assert_eq!(warp::Reply::into_response(reply).status(), 429);
+    }
+
+    #[tokio::test]
+    async fn unknown_tokens_are_rate_limited_before_introspection() {
+        let introspections = Arc::new(AtomicUsize::new(0));
+        let counter = introspections.clone();
+        let introspection = warp::post().map(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR
+        });
+        let (addr, server) = warp::serve(introspection).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::task::spawn(server);
+
+        let config = TokenBucketConfig {
+            burst: 2.0,
+            refill_rate: 0.0,
+        };
+        let principal = with_principal(
+            RateLimiter::new(config, config, None),
+            Authenticator::new(None, Some(format!("http://{}/", addr))),
+        );
+        let route = warp::path!("v2" / "deps" / String)
+            .and(principal)
+            .map(|_path: String, principal: Principal| format!("{:?}", principal));
+        let request = |token: &str| {
+            warp::test::request()
+                .path("/v2/deps/cmVhY3Q=")
+                .header("authorization", format!("Bearer {}", token))
+        };
+
+        // Failed introspections are cached, so the same token is only introspected once
+        assert!(request("a").filter(&route).await.is_ok());
+        assert!(request("a").filter(&route).await.is_ok());
+        assert_eq!(introspections.load(Ordering::SeqCst), 1);
+
+        // The ip is out of budget, so a new token gets rejected without being introspected
+        assert!(request("b").filter(&route).await.is_err());
+        assert_eq!(introspections.load(Ordering::SeqCst), 1);
+    }
+}
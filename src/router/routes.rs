@@ -1,25 +1,90 @@
-use warp::{Filter, Rejection, Reply};
+use warp::{Filter, Rejection};
 
+use crate::app_error::AppResult;
+use crate::config::{CacheTtlConfig, Config};
 use crate::npm::package_content::PackageContentFetcher;
+use crate::npm::request_stats::RequestStats;
 use crate::npm_replicator::registry::NpmRocksDB;
+use crate::npm_replicator::supervisor::ReplicationSupervisor;
 
+use super::auth::Authenticator;
+use super::custom_reply::CustomReply;
 use super::error_reply::ErrorReply;
 use super::health::health_route;
+use super::rate_limit::{get_rate_limit_reply, with_principal, RateLimiter};
+use super::routes_v2::route_batch::batch_route;
+use super::routes_v2::route_bundle::bundle_route;
+use super::routes_v2::route_cache_status::cache_status_route;
 use super::routes_v2::route_deps::deps_route;
 use super::routes_v2::route_mod::mod_route;
+use super::routes_v2::route_npm_events::npm_events_route;
 use super::routes_v2::route_npm_status::npm_sync_status_route;
+use super::routes_v2::route_pkg::pkg_route;
+use super::routes_v2::route_replication_failures::replication_failures_route;
+use super::routes_v2::route_top_packages::top_packages_route;
+use super::routes_v2::route_types::types_route;
 
 pub fn routes(
     npm_db: NpmRocksDB,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    // 15 minutes refresh interval and 1 day ttl
-    let pkg_content_fetcher = PackageContentFetcher::new();
-
-    mod_route(npm_db.clone(), pkg_content_fetcher)
-        .or(deps_route(npm_db.clone()))
-        .or(npm_sync_status_route(npm_db))
-        .or(health_route())
-        .or(not_found_route())
+    pkg_content_fetcher: PackageContentFetcher,
+    request_stats: RequestStats,
+    replication_supervisor: ReplicationSupervisor,
+    config: Config,
+) -> AppResult<impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone> {
+    let principal = with_principal(RateLimiter::from_env()?, Authenticator::from_env());
+    let cache_ttls = config.cache_ttl;
+
+    let routes = mod_route(
+        npm_db.clone(),
+        pkg_content_fetcher.clone(),
+        request_stats.clone(),
+        cache_ttls,
+        principal.clone(),
+    )
+    .or(batch_route(
+        npm_db.clone(),
+        pkg_content_fetcher.clone(),
+        cache_ttls,
+        principal.clone(),
+    ))
+    .or(bundle_route(
+        npm_db.clone(),
+        pkg_content_fetcher.clone(),
+        cache_ttls,
+        principal.clone(),
+    ))
+    .or(types_route(
+        npm_db.clone(),
+        pkg_content_fetcher.clone(),
+        cache_ttls,
+        principal.clone(),
+    ))
+    .or(deps_route(
+        npm_db.clone(),
+        request_stats.clone(),
+        cache_ttls,
+        principal.clone(),
+    ))
+    .or(pkg_route(npm_db.clone(), cache_ttls, principal.clone()))
+    .or(replication_failures_route(
+        npm_db.clone(),
+        principal.clone(),
+    ))
+    .or(top_packages_route(request_stats, principal.clone()))
+    .or(cache_status_route(pkg_content_fetcher, principal.clone()))
+    .or(npm_sync_status_route(
+        npm_db.clone(),
+        replication_supervisor.clone(),
+        cache_ttls,
+        principal.clone(),
+    ))
+    .or(health_route(replication_supervisor))
+    .with(warp::compression::gzip());
+
+    // Compression buffers the body, which would hold back server-sent events
+    let routes = npm_events_route(npm_db.hooks().clone(), principal).or(routes);
+
+    Ok(routes.recover(move |err: Rejection| handle_rejection(err, cache_ttls)))
 }
 
 pub fn with_data<T>(
@@ -31,15 +96,18 @@ where
     warp::any().map(move || data.clone())
 }
 
-pub async fn not_found_handler() -> Result<impl Reply, Rejection> {
+// Requests end up here when they're rate limited or no route matches them,
+// a rate limited route rejects so the request would otherwise fall through to the not found reply
+pub async fn handle_rejection(
+    err: Rejection,
+    cache_ttls: CacheTtlConfig,
+) -> Result<CustomReply, Rejection> {
+    if let Some(reply) = get_rate_limit_reply(&err) {
+        return Ok(reply);
+    }
     Ok(
         ErrorReply::new(404, "Not found".to_string(), "Not found".to_string())
-            .as_reply(300)
+            .as_reply(cache_ttls.errors)
             .unwrap(),
     )
 }
-
-pub fn not_found_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
-{
-    warp::any().and_then(not_found_handler)
-}
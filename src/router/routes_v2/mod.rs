@@ -1,3 +1,11 @@
 pub mod route_mod;
+pub mod route_batch;
+pub mod route_bundle;
+pub mod route_cache_status;
 pub mod route_deps;
+pub mod route_npm_events;
 pub mod route_npm_status;
+pub mod route_pkg;
+pub mod route_replication_failures;
+pub mod route_top_packages;
+pub mod route_types;
@@ -0,0 +1,170 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use warp::{Filter, Rejection, Reply};
+
+use crate::app_error::ServerError;
+use crate::config::CacheTtlConfig;
+use crate::npm::package_content::{download_package_content, PackageContentFetcher};
+use crate::npm_replicator::registry::NpmRocksDB;
+use crate::package::process::parse_package_specifier;
+use crate::router::auth::{check_access, Principal};
+use crate::router::rate_limit::PrincipalFilter;
+use crate::router::utils::decode_base64;
+
+use super::super::custom_reply::CustomReply;
+use super::super::error_reply::ErrorReply;
+use super::super::routes::with_data;
+use super::route_mod::encode_files;
+
+const MAX_BATCH_SIZE: usize = 250;
+// Amount of modules fetched at the same time for a single request
+pub const FETCH_CONCURRENCY: usize = 8;
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct BatchModule {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<HashMap<String, ByteBuf>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorReply>,
+}
+
+impl BatchModule {
+    pub fn from_result(result: Result<HashMap<String, ByteBuf>, ServerError>) -> BatchModule {
+        match result {
+            Ok(files) => BatchModule {
+                files: Some(files),
+                error: None,
+            },
+            Err(err) => BatchModule {
+                files: None,
+                error: Some(ErrorReply::from(err)),
+            },
+        }
+    }
+
+    pub fn byte_size(&self) -> usize {
+        match &self.files {
+            Some(files) => files.values().map(|content| content.len()).sum(),
+            None => 0,
+        }
+    }
+}
+
+fn parse_query(query: String) -> Result<BTreeSet<String>, ServerError> {
+    let specifiers: BTreeSet<String> = query
+        .split(';')
+        .map(|part| part.trim().to_string())
+        .filter(|part| !part.is_empty())
+        .collect();
+    if specifiers.is_empty() || specifiers.len() > MAX_BATCH_SIZE {
+        return Err(ServerError::InvalidQuery);
+    }
+    Ok(specifiers)
+}
+
+pub async fn get_module(
+    specifier: &str,
+    principal: &Principal,
+    npm_db: &NpmRocksDB,
+    pkg_content_fetcher: &PackageContentFetcher,
+) -> Result<HashMap<String, ByteBuf>, ServerError> {
+    let (pkg_name, pkg_version) = parse_package_specifier(specifier)?;
+    check_access(principal, &npm_db.registries, &pkg_name)?;
+    let content =
+        download_package_content(&pkg_name, &pkg_version, npm_db, pkg_content_fetcher).await?;
+    encode_files(content).await
+}
+
+#[tracing::instrument(
+    name = "get_batch_modules",
+    skip(principal, npm_db, pkg_content_fetcher)
+)]
+async fn get_modules(
+    specifiers: BTreeSet<String>,
+    principal: Principal,
+    npm_db: NpmRocksDB,
+    pkg_content_fetcher: PackageContentFetcher,
+) -> Result<BTreeMap<String, BatchModule>, ServerError> {
+    let semaphore = Arc::new(Semaphore::new(FETCH_CONCURRENCY));
+    let mut tasks = JoinSet::new();
+    for specifier in specifiers {
+        let semaphore = semaphore.clone();
+        let principal = principal.clone();
+        let npm_db = npm_db.clone();
+        let pkg_content_fetcher = pkg_content_fetcher.clone();
+        tasks.spawn(async move {
+            // The semaphore is never closed, so acquiring it can't fail
+            let _permit = semaphore.acquire().await;
+            let result = get_module(&specifier, &principal, &npm_db, &pkg_content_fetcher).await;
+            (specifier, result)
+        });
+    }
+
+    let mut modules: BTreeMap<String, BatchModule> = BTreeMap::new();
+    while let Some(joined) = tasks.join_next().await {
+        let (specifier, result) = joined?;
+        modules.insert(specifier, BatchModule::from_result(result));
+    }
+    Ok(modules)
+}
+
+async fn get_reply(
+    path: String,
+    principal: Principal,
+    npm_db: NpmRocksDB,
+    pkg_content_fetcher: PackageContentFetcher,
+    cache_ttls: CacheTtlConfig,
+) -> Result<CustomReply, ServerError> {
+    let decoded_query = decode_base64(&path)?;
+    let specifiers = parse_query(decoded_query)?;
+    let is_private = specifiers.iter().any(|specifier| {
+        parse_package_specifier(specifier)
+            .map(|(pkg_name, _)| npm_db.registries.is_private(&pkg_name))
+            .unwrap_or(false)
+    });
+
+    let modules = get_modules(specifiers, principal, npm_db, pkg_content_fetcher).await?;
+
+    let mut reply = CustomReply::msgpack(&modules)?;
+    // Failed entries might succeed later on, so don't cache those for too long
+    let has_errors = modules.values().any(|module| module.error.is_some());
+    let cache_ttl = match has_errors {
+        true => cache_ttls.errors,
+        false => cache_ttls.files,
+    };
+    reply.add_cache_headers(cache_ttl, is_private);
+    Ok(reply)
+}
+
+async fn batch_route_handler(
+    path: String,
+    principal: Principal,
+    npm_db: NpmRocksDB,
+    pkg_content_fetcher: PackageContentFetcher,
+    cache_ttls: CacheTtlConfig,
+) -> Result<impl Reply, Rejection> {
+    match get_reply(path, principal, npm_db, pkg_content_fetcher, cache_ttls).await {
+        Ok(reply) => Ok(reply),
+        Err(err) => Ok(ErrorReply::from(err).as_reply(cache_ttls.errors).unwrap()),
+    }
+}
+
+pub fn batch_route(
+    npm_db: NpmRocksDB,
+    pkg_content_fetcher: PackageContentFetcher,
+    cache_ttls: CacheTtlConfig,
+    principal: PrincipalFilter,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("v2" / "mods" / String)
+        .and(warp::get())
+        .and(principal)
+        .and(with_data(npm_db))
+        .and(with_data(pkg_content_fetcher))
+        .and(with_data(cache_ttls))
+        .and_then(batch_route_handler)
+}
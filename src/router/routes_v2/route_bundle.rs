@@ -0,0 +1,209 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinSet;
+use warp::{Filter, Rejection, Reply};
+
+use crate::app_error::ServerError;
+use crate::config::CacheTtlConfig;
+use crate::npm::dep_tree_builder::{ResolutionsMap, ResolverOptions};
+use crate::npm::package_content::PackageContentFetcher;
+use crate::npm_replicator::registry::NpmRocksDB;
+use crate::router::auth::{check_resolutions_access, Principal};
+use crate::router::rate_limit::PrincipalFilter;
+use crate::router::utils::decode_base64;
+
+use super::super::custom_reply::CustomReply;
+use super::super::error_reply::ErrorReply;
+use super::super::routes::with_data;
+use super::route_batch::{get_module, BatchModule, FETCH_CONCURRENCY};
+use super::route_deps::{parse_query, resolve_dep_requests};
+
+// Pages are cut off once they reach this amount of file content
+const MAX_PAGE_SIZE: usize = 10 * 1024 * 1024;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BundleQuery {
+    // The last module of the previous page, returned as its `next_cursor`
+    cursor: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct BundlePage {
+    resolutions: ResolutionsMap,
+    modules: BTreeMap<String, BatchModule>,
+    next_cursor: Option<String>,
+}
+
+// Every resolved package as an exact name@version specifier, aliases resolve to the same specifier
+fn get_specifiers(resolutions: &ResolutionsMap) -> Vec<String> {
+    let mut specifiers: BTreeSet<String> = BTreeSet::new();
+    for (key, version) in resolutions.iter() {
+        if let Some((pkg_name, _)) = key.rsplit_once('@') {
+            specifiers.insert(format!("{}@{}", pkg_name, version));
+        }
+    }
+    specifiers.into_iter().collect()
+}
+
+// Pages continue after the last module of the previous page rather than at an index,
+// the tree is resolved again for every page so the specifiers can change in between
+fn get_page_start(specifiers: &[String], cursor: Option<&str>) -> usize {
+    match cursor {
+        Some(cursor) => specifiers.partition_point(|specifier| specifier.as_str() <= cursor),
+        None => 0,
+    }
+}
+
+#[tracing::instrument(
+    name = "get_bundle_modules",
+    skip(specifiers, principal, npm_db, pkg_content_fetcher)
+)]
+async fn get_modules(
+    specifiers: &[String],
+    start: usize,
+    principal: &Principal,
+    npm_db: &NpmRocksDB,
+    pkg_content_fetcher: &PackageContentFetcher,
+) -> Result<(BTreeMap<String, BatchModule>, Option<String>), ServerError> {
+    let mut modules: BTreeMap<String, BatchModule> = BTreeMap::new();
+    let mut page_size: usize = 0;
+    let mut idx = start;
+    while idx < specifiers.len() {
+        let chunk_end = usize::min(idx + FETCH_CONCURRENCY, specifiers.len());
+        let mut tasks = JoinSet::new();
+        for (chunk_idx, specifier) in specifiers[idx..chunk_end].iter().enumerate() {
+            let specifier = specifier.clone();
+            let principal = principal.clone();
+            let npm_db = npm_db.clone();
+            let pkg_content_fetcher = pkg_content_fetcher.clone();
+            tasks.spawn(async move {
+                let result =
+                    get_module(&specifier, &principal, &npm_db, &pkg_content_fetcher).await;
+                (chunk_idx, BatchModule::from_result(result))
+            });
+        }
+
+        let mut chunk: Vec<Option<BatchModule>> = vec![None; chunk_end - idx];
+        while let Some(joined) = tasks.join_next().await {
+            let (chunk_idx, module) = joined?;
+            chunk[chunk_idx] = Some(module);
+        }
+
+        // Add modules in order, so the cursor stays deterministic
+        for module in chunk.into_iter().flatten() {
+            let module_size = module.byte_size();
+            if !modules.is_empty() && page_size + module_size > MAX_PAGE_SIZE {
+                return Ok((modules, Some(specifiers[idx - 1].clone())));
+            }
+            page_size += module_size;
+            modules.insert(specifiers[idx].clone(), module);
+            idx += 1;
+        }
+    }
+    Ok((modules, None))
+}
+
+async fn get_reply(
+    path: String,
+    query: BundleQuery,
+    options: ResolverOptions,
+    principal: Principal,
+    npm_db: NpmRocksDB,
+    pkg_content_fetcher: PackageContentFetcher,
+    cache_ttls: CacheTtlConfig,
+) -> Result<CustomReply, ServerError> {
+    let decoded_query = decode_base64(&path)?;
+    let dep_requests = parse_query(decoded_query)?;
+    let resolutions = resolve_dep_requests(dep_requests, &options, false, &principal, &npm_db)
+        .await?
+        .resolutions;
+    let is_private = check_resolutions_access(&principal, &npm_db.registries, &resolutions)?;
+
+    let specifiers = get_specifiers(&resolutions);
+    let start = get_page_start(&specifiers, query.cursor.as_deref());
+    let (modules, next_cursor) = get_modules(
+        &specifiers,
+        start,
+        &principal,
+        &npm_db,
+        &pkg_content_fetcher,
+    )
+    .await?;
+
+    // Modules that failed might work on the next try
+    let has_errors = modules.values().any(|module| module.error.is_some());
+    let cache_ttl = match has_errors {
+        true => cache_ttls.errors,
+        false => cache_ttls.resolutions,
+    };
+    let page = BundlePage {
+        resolutions,
+        modules,
+        next_cursor,
+    };
+    let mut reply = CustomReply::msgpack(&page)?;
+    reply.add_cache_headers(cache_ttl, is_private);
+    Ok(reply)
+}
+
+async fn bundle_route_handler(
+    path: String,
+    query: BundleQuery,
+    options: ResolverOptions,
+    principal: Principal,
+    npm_db: NpmRocksDB,
+    pkg_content_fetcher: PackageContentFetcher,
+    cache_ttls: CacheTtlConfig,
+) -> Result<impl Reply, Rejection> {
+    match get_reply(
+        path,
+        query,
+        options,
+        principal,
+        npm_db,
+        pkg_content_fetcher,
+        cache_ttls,
+    )
+    .await
+    {
+        Ok(reply) => Ok(reply),
+        Err(err) => Ok(ErrorReply::from(err).as_reply(cache_ttls.errors).unwrap()),
+    }
+}
+
+pub fn bundle_route(
+    npm_db: NpmRocksDB,
+    pkg_content_fetcher: PackageContentFetcher,
+    cache_ttls: CacheTtlConfig,
+    principal: PrincipalFilter,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("v2" / "bundle" / String)
+        .and(warp::get())
+        .and(warp::query::<BundleQuery>())
+        .and(warp::query::<ResolverOptions>())
+        .and(principal)
+        .and(with_data(npm_db))
+        .and(with_data(pkg_content_fetcher))
+        .and(with_data(cache_ttls))
+        .and_then(bundle_route_handler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_start() {
+        let specifiers: Vec<String> = ["a@1.0.0", "b@1.0.1", "c@1.0.0"]
+            .iter()
+            .map(|specifier| specifier.to_string())
+            .collect();
+        assert_eq!(get_page_start(&specifiers, None), 0);
+        assert_eq!(get_page_start(&specifiers, Some("a@1.0.0")), 1);
+        // b@1.0.1 got published after the previous page ended at b@1.0.0, so it still gets served
+        assert_eq!(get_page_start(&specifiers, Some("b@1.0.0")), 1);
+        assert_eq!(get_page_start(&specifiers, Some("b@1.0.1")), 2);
+        assert_eq!(get_page_start(&specifiers, Some("c@1.0.0")), 3);
+    }
+}
@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+use warp::{Filter, Rejection, Reply};
+
+use crate::app_error::ServerError;
+use crate::npm::package_content::{PackageContentFetcher, TarballCacheUsage};
+use crate::router::auth::{check_admin_access, Principal};
+use crate::router::rate_limit::PrincipalFilter;
+
+use super::super::custom_reply::CustomReply;
+use super::super::error_reply::ErrorReply;
+use super::super::routes::with_data;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+struct CacheStatus {
+    tarball_cache: TarballCacheUsage,
+}
+
+async fn get_reply(
+    principal: Principal,
+    pkg_content_fetcher: PackageContentFetcher,
+) -> Result<CustomReply, ServerError> {
+    check_admin_access(&principal)?;
+
+    let mut reply = CustomReply::json(&CacheStatus {
+        tarball_cache: pkg_content_fetcher.get_usage(),
+    })?;
+    reply.add_cache_headers(0, true);
+    Ok(reply)
+}
+
+async fn route_handler(
+    principal: Principal,
+    pkg_content_fetcher: PackageContentFetcher,
+) -> Result<impl Reply, Rejection> {
+    match get_reply(principal, pkg_content_fetcher).await {
+        Ok(reply) => Ok(reply),
+        Err(err) => Ok(ErrorReply::from(err).as_reply(0).unwrap()),
+    }
+}
+
+pub fn cache_status_route(
+    pkg_content_fetcher: PackageContentFetcher,
+    principal: PrincipalFilter,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("v2" / "admin" / "cache_status")
+        .and(warp::get())
+        .and(principal)
+        .and(with_data(pkg_content_fetcher))
+        .and_then(route_handler)
+}
@@ -1,18 +1,39 @@
 use std::collections::HashSet;
+use std::sync::Arc;
 
+use serde::{Deserialize, Serialize};
 use warp::{Filter, Rejection, Reply};
 
 use crate::app_error::{AppResult, ServerError};
-use crate::npm::dep_tree_builder::{DepRequest, DepTreeBuilder, ResolutionsMap};
+use crate::config::CacheTtlConfig;
+use crate::npm::dep_tree_builder::{
+    AccessCheck, DepRequest, DepTreeBuilder, ResolutionWarning, ResolutionsMap, ResolverOptions,
+};
+use crate::npm::request_stats::RequestStats;
 use crate::npm_replicator::registry::NpmRocksDB;
 use crate::package::process::parse_package_specifier_no_validation;
+use crate::router::auth::{check_access, check_resolutions_access, Principal};
+use crate::router::rate_limit::PrincipalFilter;
 use crate::router::utils::decode_base64;
 
 use super::super::custom_reply::CustomReply;
 use super::super::error_reply::ErrorReply;
 use super::super::routes::with_data;
 
-fn parse_query(query: String) -> Result<HashSet<DepRequest>, ServerError> {
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DepsQuery {
+    // Responds with both the resolutions and warnings instead of just the resolutions
+    include_warnings: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct ResolvedDeps {
+    pub resolutions: ResolutionsMap,
+    pub warnings: Vec<ResolutionWarning>,
+}
+
+pub fn parse_query(query: String) -> Result<HashSet<DepRequest>, ServerError> {
     let parts = query.split(';');
     let mut dep_requests: HashSet<DepRequest> = HashSet::new();
     for part in parts {
@@ -28,120 +49,249 @@ fn parse_query(query: String) -> Result<HashSet<DepRequest>, ServerError> {
     Ok(dep_requests)
 }
 
-async fn get_reply(
-    path: String,
-    npm_db: NpmRocksDB,
-    is_json: bool,
-) -> Result<CustomReply, ServerError> {
-    let decoded_query = decode_base64(&path)?;
-    let dep_requests = parse_query(decoded_query)?;
+/// Resolves the dependency tree, fetching any package that's missing from the db
+/// or doesn't have the requested version yet and retrying until it resolves.
+/// Warnings are only collected when `include_warnings` is set, otherwise they're left empty.
+/// Private packages the principal can't access fail the resolution before they're looked up or fetched.
+pub async fn resolve_dep_requests(
+    dep_requests: HashSet<DepRequest>,
+    options: &ResolverOptions,
+    include_warnings: bool,
+    principal: &Principal,
+    npm_db: &NpmRocksDB,
+) -> Result<ResolvedDeps, ServerError> {
+    for dep_request in dep_requests.iter() {
+        check_access(principal, &npm_db.registries, dep_request.name())?;
+    }
+    let access_check: AccessCheck = {
+        let principal = principal.clone();
+        let registries = npm_db.registries.clone();
+        Arc::new(move |pkg_name: &str| {
+            check_access(&principal, &registries, pkg_name).map(|_is_private| ())
+        })
+    };
 
-    let mut res_map: Option<ResolutionsMap> = None;
     let mut last_failed_pkg_name: Option<String> = None;
     for _idx in 0..100 {
         let cloned_dep_requests = dep_requests.clone();
         let cloned_npm_db = npm_db.clone();
-        let result: AppResult<ResolutionsMap> = tokio::task::spawn_blocking(move || {
-            let mut tree_builder = DepTreeBuilder::new(cloned_npm_db);
-            tree_builder.resolve_tree(cloned_dep_requests)?;
-            for (alias_key, alias_value) in tree_builder.aliases {
-                if let Some(resolved_version) = tree_builder.resolutions.get(&alias_value) {
-                    tree_builder
-                        .resolutions
-                        .insert(alias_key, resolved_version.clone());
+        let cloned_options = options.clone();
+        let cloned_access_check = access_check.clone();
+        let result: AppResult<ResolvedDeps> = npm_db
+            .spawn_blocking(move || {
+                let mut tree_builder = DepTreeBuilder::new(cloned_npm_db, cloned_options)
+                    .with_access_check(cloned_access_check);
+                tree_builder.resolve_tree(cloned_dep_requests)?;
+                let warnings = if include_warnings {
+                    tree_builder.collect_warnings()?
+                } else {
+                    Vec::new()
+                };
+                for (alias_key, alias_value) in tree_builder.aliases {
+                    if let Some(resolved_version) = tree_builder.resolutions.get(&alias_value) {
+                        tree_builder
+                            .resolutions
+                            .insert(alias_key, resolved_version.clone());
+                    }
                 }
-            }
-            Ok(tree_builder.resolutions)
-        })
-        .await?;
+                Ok(ResolvedDeps {
+                    resolutions: tree_builder.resolutions,
+                    warnings,
+                })
+            })
+            .await?;
 
         match result {
             Ok(data) => {
-                res_map = Some(data);
-                break;
+                return Ok(data);
             }
 
             Err(err) => {
-                let mut cloned_npm_db = npm_db.clone();
-                let new_pkg_name;
-                match err {
-                    ServerError::PackageVersionNotFound(pkg_name, _) => {
-                        new_pkg_name = pkg_name;
+                let (new_pkg_name, missing_range) = match &err {
+                    ServerError::PackageVersionNotFound(pkg_name, range) => {
+                        (pkg_name.clone(), Some(range.clone()))
                     }
-                    ServerError::PackageNotFound(pkg_name) => {
-                        new_pkg_name = pkg_name;
-                    }
-                    err => {
+                    ServerError::PackageNotFound(pkg_name) => (pkg_name.clone(), None),
+                    _ => {
                         return Err(err);
                     }
-                }
+                };
 
-                if new_pkg_name.len() > 0 {
-                    if Some(new_pkg_name.clone()) == last_failed_pkg_name {
-                        return Err(ServerError::PackageNotFound(new_pkg_name));
-                    }
-                    last_failed_pkg_name = Some(new_pkg_name.clone());
-                    cloned_npm_db.fetch_missing_pkg(&new_pkg_name).await?;
+                if new_pkg_name.is_empty()
+                    || npm_db.is_known_missing(&new_pkg_name, missing_range.as_deref())
+                {
+                    return Err(err);
                 }
+                // Refetching didn't help, so it really doesn't exist
+                if Some(&new_pkg_name) == last_failed_pkg_name.as_ref() {
+                    npm_db.mark_missing(&new_pkg_name, missing_range.as_deref());
+                    return Err(err);
+                }
+                last_failed_pkg_name = Some(new_pkg_name.clone());
+                npm_db.fetch_missing_pkg(&new_pkg_name).await?;
             }
         }
     }
 
-    if res_map == None {
-        return Err(ServerError::PackageNotFound(
-            last_failed_pkg_name.unwrap_or("unknown".to_string()),
-        ));
+    Err(ServerError::PackageNotFound(
+        last_failed_pkg_name.unwrap_or("unknown".to_string()),
+    ))
+}
+
+// Everything the deps routes share, bundled to keep the handlers readable
+#[derive(Clone)]
+struct DepsRouteData {
+    npm_db: NpmRocksDB,
+    request_stats: RequestStats,
+    cache_ttls: CacheTtlConfig,
+}
+
+async fn get_reply(
+    path: String,
+    query: DepsQuery,
+    options: ResolverOptions,
+    principal: Principal,
+    data: DepsRouteData,
+    is_json: bool,
+) -> Result<CustomReply, ServerError> {
+    let DepsRouteData {
+        npm_db,
+        request_stats,
+        cache_ttls,
+    } = data;
+    let decoded_query = decode_base64(&path)?;
+    let dep_requests = parse_query(decoded_query)?;
+    let requested_names: Vec<String> = dep_requests
+        .iter()
+        .map(|dep_request| String::from(dep_request.name()))
+        .collect();
+    let resolved = resolve_dep_requests(
+        dep_requests,
+        &options,
+        query.include_warnings,
+        &principal,
+        &npm_db,
+    )
+    .await?;
+    let is_private =
+        check_resolutions_access(&principal, &npm_db.registries, &resolved.resolutions)?;
+    // Only counted once everything resolved, so made up names can't fill up the stats
+    for name in requested_names.iter() {
+        request_stats.record_request(name);
     }
 
-    let mut reply = match is_json {
-        true => CustomReply::json(&res_map)?,
-        false => CustomReply::msgpack(&res_map)?,
+    let mut reply = match (is_json, query.include_warnings) {
+        (true, true) => CustomReply::json(&resolved)?,
+        (true, false) => CustomReply::json(&resolved.resolutions)?,
+        (false, true) => CustomReply::msgpack(&resolved)?,
+        (false, false) => CustomReply::msgpack(&resolved.resolutions)?,
     };
-    let cache_ttl = 3600;
-    reply.add_header(
-        "Cache-Control",
-        format!("public, max-age={}", cache_ttl).as_str(),
-    );
-    reply.add_header(
-        "CDN-Cache-Control",
-        format!("max-age={}", cache_ttl).as_str(),
-    );
+    reply.add_cache_headers(cache_ttls.resolutions, is_private);
     Ok(reply)
 }
 
 async fn deps_route_handler(
     path: String,
-    npm_db: NpmRocksDB,
+    query: DepsQuery,
+    options: ResolverOptions,
+    principal: Principal,
+    data: DepsRouteData,
     is_json: bool,
 ) -> Result<impl Reply, Rejection> {
-    match get_reply(path, npm_db, is_json).await {
+    let error_ttl = data.cache_ttls.errors;
+    match get_reply(path, query, options, principal, data, is_json).await {
         Ok(reply) => Ok(reply),
-        Err(err) => Ok(ErrorReply::from(err).as_reply(300).unwrap()),
+        Err(err) => Ok(ErrorReply::from(err).as_reply(error_ttl).unwrap()),
     }
 }
 
 fn json_route(
-    npm_db: NpmRocksDB,
+    data: DepsRouteData,
+    principal: PrincipalFilter,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("v2" / "json" / "deps" / String)
         .and(warp::get())
-        .and(with_data(npm_db))
+        .and(warp::query::<DepsQuery>())
+        .and(warp::query::<ResolverOptions>())
+        .and(principal)
+        .and(with_data(data))
         .and(with_data(true))
         .and_then(deps_route_handler)
 }
 
 fn msgpack_route(
-    npm_db: NpmRocksDB,
+    data: DepsRouteData,
+    principal: PrincipalFilter,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("v2" / "deps" / String)
         .and(warp::get())
-        .and(with_data(npm_db))
+        .and(warp::query::<DepsQuery>())
+        .and(warp::query::<ResolverOptions>())
+        .and(principal)
+        .and(with_data(data))
         .and(with_data(false))
         .and_then(deps_route_handler)
 }
 
 pub fn deps_route(
     npm_db: NpmRocksDB,
+    request_stats: RequestStats,
+    cache_ttls: CacheTtlConfig,
+    principal: PrincipalFilter,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    json_route(npm_db.clone()).or(msgpack_route(npm_db))
+    let data = DepsRouteData {
+        npm_db,
+        request_stats,
+        cache_ttls,
+    };
+    json_route(data.clone(), principal.clone()).or(msgpack_route(data, principal))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::npm::registries::RegistryConfig;
+    use crate::utils::test_utils::{create_test_db, TestPackage, TestRegistry};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn private_deps_are_not_fetched_without_access() {
+        let registry = TestRegistry::spawn(vec![
+            TestPackage {
+                name: "app",
+                version: "1.0.0",
+                dependencies: vec![("@acme/button", "^1.0.0")],
+                files: vec![],
+            },
+            TestPackage {
+                name: "@acme/button",
+                version: "1.0.0",
+                dependencies: vec![],
+                files: vec![],
+            },
+        ]);
+        let registries =
+            RegistryConfig::from_npmrc(&format!("registry={0}\n@acme:registry={0}", registry.url));
+        let route = deps_route(
+            create_test_db(registries),
+            RequestStats::default(),
+            CacheTtlConfig::default(),
+            warp::any().map(|| Principal::Anonymous).boxed(),
+        );
+        let request_deps = |query: &str| {
+            warp::test::request().path(&format!(
+                "/v2/deps/{}",
+                base64_simd::STANDARD.encode_to_string(query)
+            ))
+        };
+
+        let response = request_deps("@acme/button@^1.0.0").reply(&route).await;
+        assert_eq!(response.status(), 401);
+        assert_eq!(registry.get_request_count(), 0);
+
+        // Only the public package gets fetched, resolving stops at its private dependency
+        let response = request_deps("app@^1.0.0").reply(&route).await;
+        assert_eq!(response.status(), 401);
+        assert_eq!(registry.get_request_count(), 1);
+    }
 }
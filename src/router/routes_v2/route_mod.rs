@@ -4,9 +4,13 @@ use serde_bytes::ByteBuf;
 use warp::{Filter, Rejection, Reply};
 
 use crate::app_error::ServerError;
+use crate::config::CacheTtlConfig;
 use crate::npm::package_content::{download_package_content, FileMap, PackageContentFetcher};
+use crate::npm::request_stats::RequestStats;
 use crate::npm_replicator::registry::NpmRocksDB;
 use crate::package::process::parse_package_specifier;
+use crate::router::auth::{check_access, Principal};
+use crate::router::rate_limit::PrincipalFilter;
 use crate::router::utils::decode_base64;
 
 use super::super::custom_reply::CustomReply;
@@ -14,7 +18,7 @@ use super::super::error_reply::ErrorReply;
 use super::super::routes::with_data;
 
 #[tracing::instrument(name = "get_files", skip(files))]
-async fn encode_files(files: FileMap) -> Result<HashMap<String, ByteBuf>, ServerError> {
+pub async fn encode_files(files: FileMap) -> Result<HashMap<String, ByteBuf>, ServerError> {
     let mut encoded_files: HashMap<String, ByteBuf> = HashMap::new();
     for (filepath, content) in files.iter() {
         encoded_files.insert(filepath.clone(), ByteBuf::from(content.clone()));
@@ -23,53 +27,73 @@ async fn encode_files(files: FileMap) -> Result<HashMap<String, ByteBuf>, Server
 }
 
 #[tracing::instrument(name = "create_files_reply", skip(files))]
-async fn create_reply(files: FileMap) -> Result<CustomReply, ServerError> {
+async fn create_reply(
+    files: FileMap,
+    is_private: bool,
+    cache_ttl: u32,
+) -> Result<CustomReply, ServerError> {
     let files = encode_files(files).await?;
     let mut reply = CustomReply::msgpack(&files)?;
-    let cache_ttl = 365 * 24 * 3600;
-    reply.add_header(
-        "Cache-Control",
-        format!("public, max-age={}", cache_ttl).as_str(),
-    );
-    reply.add_header(
-        "CDN-Cache-Control",
-        format!("max-age={}", cache_ttl).as_str(),
-    );
+    reply.add_cache_headers(cache_ttl, is_private);
     Ok(reply)
 }
 
 pub async fn get_mod_reply(
     path: String,
+    principal: Principal,
     npm_db: NpmRocksDB,
     pkg_content_fetcher: PackageContentFetcher,
+    request_stats: RequestStats,
+    cache_ttls: CacheTtlConfig,
 ) -> Result<CustomReply, ServerError> {
     let decoded_specifier = decode_base64(&path)?;
     let (pkg_name, pkg_version) = parse_package_specifier(&decoded_specifier)?;
+    let is_private = check_access(&principal, &npm_db.registries, &pkg_name)?;
 
     let content =
         download_package_content(&pkg_name, &pkg_version, &npm_db, &pkg_content_fetcher).await?;
+    // Only packages that exist are counted, so made up names can't fill up the stats
+    request_stats.record_request(&pkg_name);
 
-    create_reply(content).await
+    create_reply(content, is_private, cache_ttls.files).await
 }
 
 pub async fn mod_route_handler(
     path: String,
+    principal: Principal,
     npm_db: NpmRocksDB,
     pkg_content_fetcher: PackageContentFetcher,
+    request_stats: RequestStats,
+    cache_ttls: CacheTtlConfig,
 ) -> Result<impl Reply, Rejection> {
-    match get_mod_reply(path, npm_db, pkg_content_fetcher).await {
+    match get_mod_reply(
+        path,
+        principal,
+        npm_db,
+        pkg_content_fetcher,
+        request_stats,
+        cache_ttls,
+    )
+    .await
+    {
         Ok(reply) => Ok(reply),
-        Err(err) => Ok(ErrorReply::from(err).as_reply(300).unwrap()),
+        Err(err) => Ok(ErrorReply::from(err).as_reply(cache_ttls.errors).unwrap()),
     }
 }
 
 pub fn mod_route(
     npm_db: NpmRocksDB,
     pkg_content_fetcher: PackageContentFetcher,
+    request_stats: RequestStats,
+    cache_ttls: CacheTtlConfig,
+    principal: PrincipalFilter,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("v2" / "mod" / String)
         .and(warp::get())
+        .and(principal)
         .and(with_data(npm_db))
         .and(with_data(pkg_content_fetcher))
+        .and(with_data(request_stats))
+        .and(with_data(cache_ttls))
         .and_then(mod_route_handler)
 }
@@ -0,0 +1,159 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use futures_util::stream::{self, Stream, StreamExt};
+use serde::Deserialize;
+use tokio::sync::broadcast;
+use warp::sse::Event;
+use warp::{Filter, Rejection, Reply};
+
+use crate::npm_replicator::hooks::{PackageEvent, ReplicationHooks};
+use crate::router::rate_limit::{rate_limit, PrincipalFilter};
+
+use super::super::routes::with_data;
+
+#[derive(Deserialize, Debug, Clone)]
+struct EventsQuery {
+    // Comma separated package names, all packages when empty
+    packages: Option<String>,
+    // Id of the last received event, the Last-Event-ID header takes precedence
+    since: Option<u64>,
+}
+
+fn parse_packages(packages: Option<String>) -> HashSet<String> {
+    packages
+        .unwrap_or_default()
+        .split(',')
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn to_sse_event(event: &PackageEvent) -> Result<Event, serde_json::Error> {
+    Event::default().id(event.id.to_string()).json_data(event)
+}
+
+/// Tells the client it missed events, so it should refetch the packages it cares about
+fn reset_event() -> Result<Event, serde_json::Error> {
+    Ok(Event::default()
+        .event("reset")
+        .data("Missed some events, refetch the packages"))
+}
+
+fn event_stream(
+    hooks: ReplicationHooks,
+    query: EventsQuery,
+    last_event_id: Option<u64>,
+) -> impl Stream<Item = Result<Event, serde_json::Error>> + Send + 'static {
+    let packages = Arc::new(parse_packages(query.packages));
+    let is_included =
+        move |event: &PackageEvent| packages.is_empty() || packages.contains(&event.name);
+
+    let (backlog, receiver) = match last_event_id.or(query.since) {
+        Some(since) => hooks.subscribe_since(since),
+        None => (Some(vec![]), hooks.subscribe()),
+    };
+    let backlog: Vec<Result<Event, serde_json::Error>> = match backlog {
+        Some(events) => events
+            .iter()
+            .filter(|event| is_included(event))
+            .map(to_sse_event)
+            .collect(),
+        None => vec![reset_event()],
+    };
+
+    let live = stream::unfold(receiver, move |mut receiver| {
+        let is_included = is_included.clone();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) if is_included(&event) => {
+                        return Some((to_sse_event(&event), receiver))
+                    }
+                    Ok(_) => continue,
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        return Some((reset_event(), receiver))
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+
+    stream::iter(backlog).chain(live)
+}
+
+async fn route_handler(
+    query: EventsQuery,
+    last_event_id: Option<u64>,
+    hooks: ReplicationHooks,
+) -> Result<impl Reply, Rejection> {
+    let stream = event_stream(hooks, query, last_event_id);
+    let reply = warp::sse::reply(warp::sse::keep_alive().stream(stream));
+    let reply = warp::reply::with_header(reply, "Cache-Control", "private, no-store");
+    Ok(warp::reply::with_header(
+        reply,
+        "CDN-Cache-Control",
+        "no-store",
+    ))
+}
+
+pub fn npm_events_route(
+    hooks: ReplicationHooks,
+    principal: PrincipalFilter,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("v2" / "npm_events")
+        .and(warp::get())
+        .and(rate_limit(principal))
+        .and(warp::query::<EventsQuery>())
+        .and(warp::sse::last_event_id::<u64>())
+        .and(with_data(hooks))
+        .and_then(route_handler)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::npm::registries::RegistryConfig;
+    use crate::utils::test_utils::{create_test_db, TestPackage, TestRegistry};
+
+    use super::*;
+
+    #[test]
+    fn package_filter() {
+        assert!(parse_packages(None).is_empty());
+        let packages = parse_packages(Some(String::from("react, react-dom,,@babel/core")));
+        assert_eq!(packages.len(), 3);
+        assert!(packages.contains("react-dom"));
+        assert!(packages.contains("@babel/core"));
+    }
+
+    #[tokio::test]
+    async fn private_packages_are_left_out() {
+        let registry = TestRegistry::spawn(
+            ["@acme/secret", "react"]
+                .into_iter()
+                .map(|name| TestPackage {
+                    name,
+                    version: "1.0.0",
+                    dependencies: vec![],
+                    files: vec![],
+                })
+                .collect(),
+        );
+        let registries =
+            RegistryConfig::from_npmrc(&format!("registry={0}\n@acme:registry={0}", registry.url));
+        let npm_db = create_test_db(registries);
+        let query = EventsQuery {
+            packages: None,
+            since: None,
+        };
+        let mut stream = Box::pin(event_stream(npm_db.hooks().clone(), query, None));
+
+        npm_db.fetch_missing_pkg("@acme/secret").await.unwrap();
+        npm_db.fetch_missing_pkg("react").await.unwrap();
+        let event = stream.next().await.unwrap().unwrap().to_string();
+        assert!(event.contains(r#""name":"react""#));
+        assert!(!event.contains("@acme/secret"));
+    }
+}
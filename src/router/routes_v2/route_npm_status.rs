@@ -2,46 +2,78 @@ use serde::{Deserialize, Serialize};
 use warp::{Filter, Rejection, Reply};
 
 use crate::app_error::{AppResult, ServerError};
+use crate::config::CacheTtlConfig;
 use crate::npm_replicator::registry::NpmRocksDB;
+use crate::npm_replicator::stats::ReplicationStatsSnapshot;
+use crate::npm_replicator::supervisor::{ReplicationSupervisor, SupervisorStatus};
+use crate::router::rate_limit::{rate_limit, PrincipalFilter};
 
 use super::super::custom_reply::CustomReply;
 use super::super::error_reply::ErrorReply;
 use super::super::routes::with_data;
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 struct NpmSyncStatus {
     last_seq: i64,
+    // Amount of changes in the npm registry that haven't been processed yet
+    lag: Option<i64>,
+    #[serde(flatten)]
+    stats: ReplicationStatsSnapshot,
+    replication: SupervisorStatus,
 }
 
-async fn get_reply(npm_db: NpmRocksDB) -> Result<CustomReply, ServerError> {
-    let status: AppResult<NpmSyncStatus> = tokio::task::spawn_blocking(move || {
-        let last_seq = npm_db.get_last_seq()?;
+async fn get_reply(
+    npm_db: NpmRocksDB,
+    replication_supervisor: ReplicationSupervisor,
+    cache_ttls: CacheTtlConfig,
+) -> Result<CustomReply, ServerError> {
+    let cloned_npm_db = npm_db.clone();
+    let status: AppResult<NpmSyncStatus> = npm_db
+        .spawn_blocking(move || {
+            let last_seq = cloned_npm_db.get_last_seq()?;
 
-        Ok(NpmSyncStatus { last_seq })
-    })
-    .await?;
+            let stats = replication_supervisor.stats().get_snapshot();
+            Ok(NpmSyncStatus {
+                last_seq,
+                lag: stats
+                    .update_seq
+                    .map(|update_seq| i64::max(0, update_seq - last_seq)),
+                stats,
+                replication: replication_supervisor.get_status(),
+            })
+        })
+        .await?;
 
     let mut reply = CustomReply::json(&status?)?;
-    let cache_ttl = 300;
     reply.add_header(
         "Cache-Control",
-        format!("public, max-age={}", cache_ttl).as_str(),
+        format!("public, max-age={}", cache_ttls.sync_status).as_str(),
     );
     Ok(reply)
 }
 
-async fn route_handler(npm_db: NpmRocksDB) -> Result<impl Reply, Rejection> {
-    match get_reply(npm_db).await {
+async fn route_handler(
+    npm_db: NpmRocksDB,
+    replication_supervisor: ReplicationSupervisor,
+    cache_ttls: CacheTtlConfig,
+) -> Result<impl Reply, Rejection> {
+    match get_reply(npm_db, replication_supervisor, cache_ttls).await {
         Ok(reply) => Ok(reply),
-        Err(err) => Ok(ErrorReply::from(err).as_reply(300).unwrap()),
+        Err(err) => Ok(ErrorReply::from(err).as_reply(cache_ttls.errors).unwrap()),
     }
 }
 
 pub fn npm_sync_status_route(
     npm_db: NpmRocksDB,
+    replication_supervisor: ReplicationSupervisor,
+    cache_ttls: CacheTtlConfig,
+    principal: PrincipalFilter,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("v2" / "npm_sync_status")
         .and(warp::get())
+        .and(rate_limit(principal))
         .and(with_data(npm_db))
+        .and(with_data(replication_supervisor))
+        .and(with_data(cache_ttls))
         .and_then(route_handler)
 }
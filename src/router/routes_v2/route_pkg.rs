@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use node_semver::{Range, Version};
+use serde::{Deserialize, Serialize};
+use warp::{Filter, Rejection, Reply};
+
+use crate::app_error::ServerError;
+use crate::config::CacheTtlConfig;
+use crate::npm_replicator::registry::NpmRocksDB;
+use crate::npm_replicator::types::document::MinimalPackageData;
+use crate::router::auth::{check_access, Principal};
+use crate::router::rate_limit::PrincipalFilter;
+use crate::router::utils::decode_base64;
+
+use super::super::custom_reply::CustomReply;
+use super::super::error_reply::ErrorReply;
+use super::super::routes::with_data;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PkgQuery {
+    // Only return versions that satisfy this semver range
+    range: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct PackageInfo {
+    name: String,
+    dist_tags: BTreeMap<String, String>,
+    // Sorted from lowest to highest semver precedence
+    versions: Vec<String>,
+    // Seconds since the epoch
+    last_updated: Option<u64>,
+}
+
+impl PackageInfo {
+    fn from_package_data(data: &MinimalPackageData, range: Option<&Range>) -> PackageInfo {
+        let mut versions: Vec<Version> = Vec::new();
+        for version in data.versions.keys() {
+            let parsed_version = match Version::parse(version) {
+                Ok(parsed_version) => parsed_version,
+                Err(_err) => continue,
+            };
+            if let Some(range) = range {
+                if !range.satisfies(&parsed_version) {
+                    continue;
+                }
+            }
+            versions.push(parsed_version);
+        }
+        versions.sort();
+
+        PackageInfo {
+            name: data.name.clone(),
+            dist_tags: data.dist_tags.clone(),
+            versions: versions.iter().map(|v| v.to_string()).collect(),
+            last_updated: data.last_updated,
+        }
+    }
+}
+
+async fn get_package(
+    pkg_name: &str,
+    npm_db: &NpmRocksDB,
+) -> Result<Arc<MinimalPackageData>, ServerError> {
+    match npm_db.get_package_async(pkg_name).await {
+        Ok(pkg) => Ok(pkg),
+        Err(ServerError::PackageNotFound(_)) => {
+            npm_db.fetch_missing_pkg(pkg_name).await?;
+            npm_db.get_package_async(pkg_name).await
+        }
+        Err(err) => Err(err),
+    }
+}
+
+async fn get_reply(
+    path: String,
+    query: PkgQuery,
+    principal: Principal,
+    npm_db: NpmRocksDB,
+    is_json: bool,
+    cache_ttls: CacheTtlConfig,
+) -> Result<CustomReply, ServerError> {
+    let pkg_name = decode_base64(&path)?;
+    let is_private = check_access(&principal, &npm_db.registries, pkg_name.trim())?;
+    let range = match query.range {
+        Some(range) => Some(Range::parse(range).map_err(|_err| ServerError::InvalidQuery)?),
+        None => None,
+    };
+
+    let pkg = get_package(pkg_name.trim(), &npm_db).await?;
+    let info = PackageInfo::from_package_data(&pkg, range.as_ref());
+
+    let mut reply = match is_json {
+        true => CustomReply::json(&info)?,
+        false => CustomReply::msgpack(&info)?,
+    };
+    reply.add_cache_headers(cache_ttls.metadata, is_private);
+    Ok(reply)
+}
+
+async fn pkg_route_handler(
+    path: String,
+    query: PkgQuery,
+    principal: Principal,
+    npm_db: NpmRocksDB,
+    is_json: bool,
+    cache_ttls: CacheTtlConfig,
+) -> Result<impl Reply, Rejection> {
+    match get_reply(path, query, principal, npm_db, is_json, cache_ttls).await {
+        Ok(reply) => Ok(reply),
+        Err(err) => Ok(ErrorReply::from(err).as_reply(cache_ttls.errors).unwrap()),
+    }
+}
+
+fn json_route(
+    npm_db: NpmRocksDB,
+    cache_ttls: CacheTtlConfig,
+    principal: PrincipalFilter,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("v2" / "json" / "pkg" / String)
+        .and(warp::get())
+        .and(warp::query::<PkgQuery>())
+        .and(principal)
+        .and(with_data(npm_db))
+        .and(with_data(true))
+        .and(with_data(cache_ttls))
+        .and_then(pkg_route_handler)
+}
+
+fn msgpack_route(
+    npm_db: NpmRocksDB,
+    cache_ttls: CacheTtlConfig,
+    principal: PrincipalFilter,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("v2" / "pkg" / String)
+        .and(warp::get())
+        .and(warp::query::<PkgQuery>())
+        .and(principal)
+        .and(with_data(npm_db))
+        .and(with_data(false))
+        .and(with_data(cache_ttls))
+        .and_then(pkg_route_handler)
+}
+
+pub fn pkg_route(
+    npm_db: NpmRocksDB,
+    cache_ttls: CacheTtlConfig,
+    principal: PrincipalFilter,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    json_route(npm_db.clone(), cache_ttls, principal.clone())
+        .or(msgpack_route(npm_db, cache_ttls, principal))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::test_utils::{create_test_db, TestPackage, TestRegistry};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn versions_in_range() {
+        let registry = TestRegistry::spawn(
+            ["1.9.0", "1.10.0", "2.0.0"]
+                .into_iter()
+                .map(|version| TestPackage {
+                    name: "lib",
+                    version,
+                    dependencies: vec![],
+                    files: vec![],
+                })
+                .collect(),
+        );
+        let route = pkg_route(
+            create_test_db(registry.get_registries()),
+            CacheTtlConfig::default(),
+            warp::any().map(|| Principal::Anonymous).boxed(),
+        );
+        let request_pkg = |query: &str| {
+            warp::test::request().path(&format!(
+                "/v2/json/pkg/{}{}",
+                base64_simd::STANDARD.encode_to_string("lib"),
+                query
+            ))
+        };
+
+        // Packages that aren't in the db yet get fetched from npm
+        let response = request_pkg("").reply(&route).await;
+        assert_eq!(response.status(), 200);
+        let info: PackageInfo = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(info.name, "lib");
+        assert_eq!(info.versions, vec!["1.9.0", "1.10.0", "2.0.0"]);
+        assert_eq!(registry.get_request_count(), 1);
+
+        let response = request_pkg("?range=%5E1.0.0").reply(&route).await;
+        assert_eq!(response.status(), 200);
+        let info: PackageInfo = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(info.versions, vec!["1.9.0", "1.10.0"]);
+        assert_eq!(registry.get_request_count(), 1);
+
+        let response = request_pkg("?range=%5Enope").reply(&route).await;
+        assert_eq!(response.status(), 400);
+    }
+}
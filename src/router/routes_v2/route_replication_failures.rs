@@ -0,0 +1,56 @@
+use serde::Serialize;
+use warp::{Filter, Rejection, Reply};
+
+use crate::app_error::{AppResult, ServerError};
+use crate::npm_replicator::registry::NpmRocksDB;
+use crate::npm_replicator::retry_queue::RetryEntry;
+use crate::router::auth::{check_admin_access, Principal};
+use crate::router::rate_limit::PrincipalFilter;
+
+use super::super::custom_reply::CustomReply;
+use super::super::error_reply::ErrorReply;
+use super::super::routes::with_data;
+
+#[derive(Serialize, Debug, Clone)]
+struct ReplicationFailures {
+    // Packages that failed to replicate and are retried with backoff
+    retrying: Vec<RetryEntry>,
+    // Packages that kept failing, these are only retried once an hour
+    dead_letters: Vec<RetryEntry>,
+}
+
+async fn get_reply(principal: Principal, npm_db: NpmRocksDB) -> Result<CustomReply, ServerError> {
+    check_admin_access(&principal)?;
+
+    let cloned_npm_db = npm_db.clone();
+    let failures: AppResult<ReplicationFailures> = npm_db
+        .spawn_blocking(move || {
+            Ok(ReplicationFailures {
+                retrying: cloned_npm_db.get_retry_entries()?,
+                dead_letters: cloned_npm_db.get_dead_letters()?,
+            })
+        })
+        .await?;
+
+    let mut reply = CustomReply::json(&failures?)?;
+    reply.add_cache_headers(0, true);
+    Ok(reply)
+}
+
+async fn route_handler(principal: Principal, npm_db: NpmRocksDB) -> Result<impl Reply, Rejection> {
+    match get_reply(principal, npm_db).await {
+        Ok(reply) => Ok(reply),
+        Err(err) => Ok(ErrorReply::from(err).as_reply(0).unwrap()),
+    }
+}
+
+pub fn replication_failures_route(
+    npm_db: NpmRocksDB,
+    principal: PrincipalFilter,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("v2" / "admin" / "replication_failures")
+        .and(warp::get())
+        .and(principal)
+        .and(with_data(npm_db))
+        .and_then(route_handler)
+}
@@ -0,0 +1,54 @@
+use serde::Deserialize;
+use warp::{Filter, Rejection, Reply};
+
+use crate::app_error::ServerError;
+use crate::npm::request_stats::RequestStats;
+use crate::router::auth::{check_admin_access, Principal};
+use crate::router::rate_limit::PrincipalFilter;
+
+use super::super::custom_reply::CustomReply;
+use super::super::error_reply::ErrorReply;
+use super::super::routes::with_data;
+
+const MAX_LIMIT: usize = 1000;
+
+#[derive(Deserialize, Debug, Clone)]
+struct TopPackagesQuery {
+    limit: Option<usize>,
+}
+
+async fn get_reply(
+    query: TopPackagesQuery,
+    principal: Principal,
+    request_stats: RequestStats,
+) -> Result<CustomReply, ServerError> {
+    check_admin_access(&principal)?;
+
+    let limit = usize::min(query.limit.unwrap_or(100), MAX_LIMIT);
+    let mut reply = CustomReply::json(&request_stats.get_top(limit))?;
+    reply.add_cache_headers(0, true);
+    Ok(reply)
+}
+
+async fn route_handler(
+    query: TopPackagesQuery,
+    principal: Principal,
+    request_stats: RequestStats,
+) -> Result<impl Reply, Rejection> {
+    match get_reply(query, principal, request_stats).await {
+        Ok(reply) => Ok(reply),
+        Err(err) => Ok(ErrorReply::from(err).as_reply(0).unwrap()),
+    }
+}
+
+pub fn top_packages_route(
+    request_stats: RequestStats,
+    principal: PrincipalFilter,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("v2" / "admin" / "top_packages")
+        .and(warp::get())
+        .and(warp::query::<TopPackagesQuery>())
+        .and(principal)
+        .and(with_data(request_stats))
+        .and_then(route_handler)
+}
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+
+use node_semver::{Range, Version};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use warp::{Filter, Rejection, Reply};
+
+use crate::app_error::ServerError;
+use crate::config::CacheTtlConfig;
+use crate::npm::dep_tree_builder::{find_matching_version, ResolverOptions};
+use crate::npm::package_content::{download_package_content, FileMap, PackageContentFetcher};
+use crate::npm_replicator::registry::NpmRocksDB;
+use crate::npm_replicator::types::document::MinimalPackageData;
+use crate::package::process::parse_package_specifier;
+use crate::router::auth::{check_access, Principal};
+use crate::router::rate_limit::PrincipalFilter;
+use crate::router::utils::decode_base64;
+
+use super::super::custom_reply::CustomReply;
+use super::super::error_reply::ErrorReply;
+use super::super::routes::with_data;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct TypesResponse {
+    // The package the types were taken from, either the package itself or its @types package
+    name: String,
+    version: String,
+    // The `types` or `typings` entry of the package.json
+    types: Option<String>,
+    files: HashMap<String, ByteBuf>,
+}
+
+fn is_declaration_file(filepath: &str) -> bool {
+    filepath.ends_with(".d.ts") || filepath.ends_with(".d.mts") || filepath.ends_with(".d.cts")
+}
+
+fn get_types_entry(files: &FileMap) -> Option<String> {
+    let pkg_json_content = files.get("/package.json")?;
+    let pkg_json: serde_json::Value = serde_json::from_slice(pkg_json_content).ok()?;
+    pkg_json
+        .get("types")
+        .or_else(|| pkg_json.get("typings"))
+        .and_then(|entry| entry.as_str())
+        .map(String::from)
+}
+
+fn collect_types(name: &str, version: &str, files: FileMap) -> TypesResponse {
+    let mut declaration_files: HashMap<String, ByteBuf> = HashMap::new();
+    for (filepath, content) in files.iter() {
+        if is_declaration_file(filepath) {
+            declaration_files.insert(filepath.clone(), ByteBuf::from(content.clone()));
+        }
+    }
+
+    TypesResponse {
+        name: String::from(name),
+        version: String::from(version),
+        types: get_types_entry(&files),
+        files: declaration_files,
+    }
+}
+
+fn has_own_types(types: &TypesResponse) -> bool {
+    types.types.is_some() || types.files.contains_key("/index.d.ts")
+}
+
+// Example: @babel/core => @types/babel__core
+fn get_types_pkg_name(pkg_name: &str) -> String {
+    match pkg_name.strip_prefix('@') {
+        Some(scoped_name) => format!("@types/{}", scoped_name.replacen('/', "__", 1)),
+        None => format!("@types/{}", pkg_name),
+    }
+}
+
+/// Finds the @types version matching the given version, without falling back to another major version.
+/// DefinitelyTyped versions follow the major and minor version of the package they describe
+fn find_types_version(
+    data: &MinimalPackageData,
+    pkg_version: &Version,
+) -> Result<Option<Version>, ServerError> {
+    let ranges = [
+        format!("~{}.{}", pkg_version.major, pkg_version.minor),
+        format!("^{}", pkg_version.major),
+    ];
+    for range in ranges {
+        let range = Range::parse(range)?;
+        if let Some(version) = find_matching_version(data, &range, &ResolverOptions::default())? {
+            return Ok(Some(version));
+        }
+    }
+    Ok(None)
+}
+
+/// Resolves the @types package matching the given version,
+/// fetching it from npm when it's missing or doesn't have a matching version yet
+async fn resolve_types_pkg(
+    types_pkg_name: &str,
+    pkg_version: &str,
+    principal: &Principal,
+    npm_db: &NpmRocksDB,
+) -> Result<Option<String>, ServerError> {
+    check_access(principal, &npm_db.registries, types_pkg_name)?;
+    let version = Version::parse(pkg_version)?;
+
+    match npm_db.get_package_async(types_pkg_name).await {
+        Ok(data) => {
+            if let Some(types_version) = find_types_version(&data, &version)? {
+                return Ok(Some(types_version.to_string()));
+            }
+        }
+        Err(ServerError::PackageNotFound(_)) => {}
+        Err(err) => {
+            return Err(err);
+        }
+    }
+
+    // Fetching a package that doesn't exist on npm gives a 404
+    match npm_db.fetch_missing_pkg(types_pkg_name).await {
+        Ok(()) => {}
+        Err(ServerError::PackageNotFound(_))
+        | Err(ServerError::PackageMetadataDownloadError {
+            status_code: 404, ..
+        }) => {
+            return Ok(None);
+        }
+        Err(err) => {
+            return Err(err);
+        }
+    }
+
+    match npm_db.get_package_async(types_pkg_name).await {
+        Ok(data) => Ok(find_types_version(&data, &version)?.map(|version| version.to_string())),
+        Err(ServerError::PackageNotFound(_)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+async fn get_reply(
+    path: String,
+    principal: Principal,
+    npm_db: NpmRocksDB,
+    pkg_content_fetcher: PackageContentFetcher,
+    cache_ttls: CacheTtlConfig,
+) -> Result<CustomReply, ServerError> {
+    let decoded_specifier = decode_base64(&path)?;
+    let (pkg_name, pkg_version) = parse_package_specifier(&decoded_specifier)?;
+    let is_private = check_access(&principal, &npm_db.registries, &pkg_name)?;
+
+    let content =
+        download_package_content(&pkg_name, &pkg_version, &npm_db, &pkg_content_fetcher).await?;
+    let mut types = collect_types(&pkg_name, &pkg_version, content);
+
+    // The resolved @types version can change whenever a new version gets published
+    let mut cache_ttl = cache_ttls.files;
+    if !has_own_types(&types) && !pkg_name.starts_with("@types/") {
+        let types_pkg_name = get_types_pkg_name(&pkg_name);
+        if let Some(types_version) =
+            resolve_types_pkg(&types_pkg_name, &pkg_version, &principal, &npm_db).await?
+        {
+            let content = download_package_content(
+                &types_pkg_name,
+                &types_version,
+                &npm_db,
+                &pkg_content_fetcher,
+            )
+            .await?;
+            types = collect_types(&types_pkg_name, &types_version, content);
+        }
+        cache_ttl = cache_ttls.resolutions;
+    }
+
+    let mut reply = CustomReply::msgpack(&types)?;
+    reply.add_cache_headers(cache_ttl, is_private);
+    Ok(reply)
+}
+
+async fn types_route_handler(
+    path: String,
+    principal: Principal,
+    npm_db: NpmRocksDB,
+    pkg_content_fetcher: PackageContentFetcher,
+    cache_ttls: CacheTtlConfig,
+) -> Result<impl Reply, Rejection> {
+    match get_reply(path, principal, npm_db, pkg_content_fetcher, cache_ttls).await {
+        Ok(reply) => Ok(reply),
+        Err(err) => Ok(ErrorReply::from(err).as_reply(cache_ttls.errors).unwrap()),
+    }
+}
+
+pub fn types_route(
+    npm_db: NpmRocksDB,
+    pkg_content_fetcher: PackageContentFetcher,
+    cache_ttls: CacheTtlConfig,
+    principal: PrincipalFilter,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("v2" / "types" / String)
+        .and(warp::get())
+        .and(principal)
+        .and(with_data(npm_db))
+        .and(with_data(pkg_content_fetcher))
+        .and(with_data(cache_ttls))
+        .and_then(types_route_handler)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::test_utils::{create_test_db, TestPackage, TestRegistry};
+
+    use super::*;
+
+    #[test]
+    fn types_pkg_name() {
+        assert_eq!(get_types_pkg_name("react"), "@types/react");
+        assert_eq!(get_types_pkg_name("@babel/core"), "@types/babel__core");
+    }
+
+    #[tokio::test]
+    async fn no_types_pkg() {
+        let registry = TestRegistry::spawn(vec![TestPackage {
+            name: "untyped",
+            version: "1.0.0",
+            dependencies: vec![],
+            files: vec![
+                ("package.json", r#"{ "name": "untyped" }"#),
+                ("index.js", ""),
+            ],
+        }]);
+        let npm_db = create_test_db(registry.get_registries());
+        let pkg_content_fetcher = PackageContentFetcher::new(
+            npm_db.registries.clone(),
+            &crate::config::TarballCacheConfig::default(),
+        );
+        let route = types_route(
+            npm_db,
+            pkg_content_fetcher,
+            CacheTtlConfig::default(),
+            warp::any().map(|| Principal::Anonymous).boxed(),
+        );
+
+        let specifier = base64_simd::STANDARD.encode_to_string("untyped@1.0.0");
+        let response = warp::test::request()
+            .path(&format!("/v2/types/{}", specifier))
+            .reply(&route)
+            .await;
+        assert_eq!(response.status(), 200);
+        let types: TypesResponse = rmp_serde::from_slice(response.body()).unwrap();
+        assert_eq!(types.name, "untyped");
+        assert!(types.types.is_none());
+        assert!(types.files.is_empty());
+    }
+
+    #[tokio::test]
+    async fn types_pkg_of_same_major() {
+        let registry = TestRegistry::spawn(vec![
+            TestPackage {
+                name: "lib",
+                version: "2.3.0",
+                dependencies: vec![],
+                files: vec![("package.json", r#"{ "name": "lib" }"#)],
+            },
+            TestPackage {
+                name: "lib",
+                version: "3.0.0",
+                dependencies: vec![],
+                files: vec![("package.json", r#"{ "name": "lib" }"#)],
+            },
+            TestPackage {
+                name: "@types/lib",
+                version: "1.0.0",
+                dependencies: vec![],
+                files: vec![("index.d.ts", "export {};")],
+            },
+            TestPackage {
+                name: "@types/lib",
+                version: "2.1.0",
+                dependencies: vec![],
+                files: vec![("index.d.ts", "export {};")],
+            },
+        ]);
+        let npm_db = create_test_db(registry.get_registries());
+        let pkg_content_fetcher = PackageContentFetcher::new(
+            npm_db.registries.clone(),
+            &crate::config::TarballCacheConfig::default(),
+        );
+        let route = types_route(
+            npm_db,
+            pkg_content_fetcher,
+            CacheTtlConfig::default(),
+            warp::any().map(|| Principal::Anonymous).boxed(),
+        );
+        let request_types = |specifier: &str| {
+            warp::test::request().path(&format!(
+                "/v2/types/{}",
+                base64_simd::STANDARD.encode_to_string(specifier)
+            ))
+        };
+
+        // There's no @types/lib 2.3.x, so it falls back to the highest 2.x
+        let response = request_types("lib@2.3.0").reply(&route).await;
+        assert_eq!(response.status(), 200);
+        let types: TypesResponse = rmp_serde::from_slice(response.body()).unwrap();
+        assert_eq!(types.name, "@types/lib");
+        assert_eq!(types.version, "2.1.0");
+        assert!(types.files.contains_key("/index.d.ts"));
+
+        // Types of another major version would be wrong, so there are none
+        let response = request_types("lib@3.0.0").reply(&route).await;
+        assert_eq!(response.status(), 200);
+        let types: TypesResponse = rmp_serde::from_slice(response.body()).unwrap();
+        assert_eq!(types.name, "lib");
+        assert!(types.files.is_empty());
+    }
+}
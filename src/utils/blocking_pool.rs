@@ -0,0 +1,52 @@
+use std::sync::Arc;
+
+use tokio::runtime::{Builder, Runtime};
+
+use crate::app_error::ServerError;
+
+struct PoolRuntime(Option<Runtime>);
+
+// Dropping a runtime blocks, which panics when the last handle gets dropped inside an async task
+impl Drop for PoolRuntime {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.0.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// Runs blocking work on its own set of threads, so slow disk reads can't
+/// starve tokio's shared blocking pool that everything else depends on
+#[derive(Clone)]
+pub struct BlockingPool {
+    runtime: Arc<PoolRuntime>,
+}
+
+impl BlockingPool {
+    pub fn new(name: &str, max_threads: usize) -> BlockingPool {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(1)
+            .max_blocking_threads(max_threads)
+            .thread_name(name)
+            .build()
+            .expect("Failed to create blocking pool");
+        BlockingPool {
+            runtime: Arc::new(PoolRuntime(Some(runtime))),
+        }
+    }
+
+    pub async fn run<F, T>(&self, func: F) -> Result<T, ServerError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let runtime = self.runtime.0.as_ref().expect("Blocking pool is shut down");
+        Ok(runtime.spawn_blocking(func).await?)
+    }
+}
+
+impl std::fmt::Debug for BlockingPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("BlockingPool")
+    }
+}
@@ -1,3 +1,6 @@
+#[cfg(test)]
 pub mod test_utils;
 pub mod msgpack;
-pub mod time;
\ No newline at end of file
+pub mod blocking_pool;
+pub mod time;
+pub mod token_bucket;
@@ -1,7 +1,21 @@
+use std::collections::BTreeMap;
 use std::env;
 use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use flate2::{write::GzEncoder, Compression};
+use serde_json::json;
+use warp::http::{Response, StatusCode};
+use warp::path::FullPath;
+use warp::Filter;
 
 use crate::app_error::ServerError;
+use crate::config::{DatabaseConfig, PackageCacheConfig};
+use crate::npm::registries::RegistryConfig;
+use crate::npm_replicator::registry::NpmRocksDB;
+
+static TEST_DB_COUNT: AtomicUsize = AtomicUsize::new(0);
 
 #[allow(dead_code)]
 pub fn read_fixture(fixture_name: &str) -> Result<String, ServerError> {
@@ -9,3 +23,116 @@ pub fn read_fixture(fixture_name: &str) -> Result<String, ServerError> {
     let fixture_content: String = fs::read_to_string(fixture_path)?;
     Ok(fixture_content)
 }
+
+/// An empty db in the temp dir
+pub fn create_test_db(registries: RegistryConfig) -> NpmRocksDB {
+    let db_path = env::temp_dir().join(format!(
+        "sandpack-cdn-test-{}-{}",
+        std::process::id(),
+        TEST_DB_COUNT.fetch_add(1, Ordering::SeqCst)
+    ));
+    NpmRocksDB::new(
+        db_path.to_str().unwrap(),
+        registries,
+        PackageCacheConfig::default(),
+        DatabaseConfig::default(),
+    )
+    .unwrap()
+}
+
+pub struct TestPackage {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub dependencies: Vec<(&'static str, &'static str)>,
+    pub files: Vec<(&'static str, &'static str)>,
+}
+
+fn create_tarball(files: &[(&str, &str)]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for (filepath, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(
+                &mut header,
+                format!("package/{}", filepath),
+                content.as_bytes(),
+            )
+            .unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap()
+}
+
+/// A local npm registry serving the given packages, everything else is a 404
+pub struct TestRegistry {
+    pub url: String,
+    request_count: Arc<AtomicUsize>,
+}
+
+impl TestRegistry {
+    pub fn spawn(packages: Vec<TestPackage>) -> TestRegistry {
+        let packages = Arc::new(packages);
+        let request_count = Arc::new(AtomicUsize::new(0));
+        let counter = request_count.clone();
+        let filter = warp::path::full().and(warp::header::<String>("host")).map(
+            move |path: FullPath, host: String| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let path = path.as_str().trim_start_matches('/');
+
+                // Tarballs are served from <name>/-/<version>.tgz
+                if let Some((name, file)) = path.split_once("/-/") {
+                    let version = file.trim_end_matches(".tgz");
+                    if let Some(pkg) = packages
+                        .iter()
+                        .find(|pkg| pkg.name == name && pkg.version == version)
+                    {
+                        return Response::new(create_tarball(&pkg.files));
+                    }
+                }
+
+                let mut versions = BTreeMap::new();
+                for pkg in packages.iter().filter(|pkg| pkg.name == path) {
+                    let tarball = format!("http://{}/{}/-/{}.tgz", host, pkg.name, pkg.version);
+                    let dependencies: BTreeMap<_, _> = pkg.dependencies.iter().cloned().collect();
+                    versions.insert(
+                        pkg.version,
+                        json!({ "dependencies": dependencies, "dist": { "tarball": tarball } }),
+                    );
+                }
+                match versions.keys().last() {
+                    Some(latest) => {
+                        let metadata = json!({
+                            "name": path,
+                            "dist-tags": { "latest": latest },
+                            "versions": versions,
+                        });
+                        Response::new(serde_json::to_vec(&metadata).unwrap())
+                    }
+                    None => {
+                        let mut response = Response::new(Vec::new());
+                        *response.status_mut() = StatusCode::NOT_FOUND;
+                        response
+                    }
+                }
+            },
+        );
+        let (addr, server) = warp::serve(filter).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::task::spawn(server);
+
+        TestRegistry {
+            url: format!("http://{}/", addr),
+            request_count,
+        }
+    }
+
+    /// Routes all packages to this registry
+    pub fn get_registries(&self) -> RegistryConfig {
+        RegistryConfig::from_npmrc(&format!("registry={}", self.url))
+    }
+
+    pub fn get_request_count(&self) -> usize {
+        self.request_count.load(Ordering::SeqCst)
+    }
+}
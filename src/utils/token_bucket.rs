@@ -0,0 +1,112 @@
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TokenBucketConfig {
+    // Amount of requests that can be done at once
+    pub burst: f64,
+    // Amount of tokens added back per second
+    pub refill_rate: f64,
+}
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    config: TokenBucketConfig,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(config: TokenBucketConfig, now: Instant) -> TokenBucket {
+        TokenBucket {
+            config,
+            tokens: config.burst,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = f64::min(
+            self.config.burst,
+            self.tokens + elapsed * self.config.refill_rate,
+        );
+        self.updated_at = now;
+    }
+
+    /// Takes a single token, returns how long to wait for the next token if the bucket is empty
+    pub fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+
+        if self.config.refill_rate <= 0.0 {
+            return Err(Duration::MAX);
+        }
+        let missing = 1.0 - self.tokens;
+        Err(Duration::from_secs_f64(missing / self.config.refill_rate))
+    }
+
+    /// Takes any amount, even more than is available, for costs that are only known afterwards
+    pub fn consume(&mut self, amount: f64, now: Instant) {
+        self.refill(now);
+        self.tokens -= amount;
+    }
+
+    /// Gives back tokens that were taken, up to the burst
+    pub fn refund(&mut self, amount: f64, now: Instant) {
+        self.refill(now);
+        self.tokens = f64::min(self.config.burst, self.tokens + amount);
+    }
+
+    /// How long until the bucket is out of debt again
+    pub fn time_until_available(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        if self.config.refill_rate <= 0.0 {
+            return Duration::MAX;
+        }
+        Duration::from_secs_f64(-self.tokens / self.config.refill_rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_and_refill() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(
+            TokenBucketConfig {
+                burst: 2.0,
+                refill_rate: 1.0,
+            },
+            now,
+        );
+
+        assert!(bucket.try_take(now).is_ok());
+        assert!(bucket.try_take(now).is_ok());
+        assert_eq!(bucket.try_take(now), Err(Duration::from_secs(1)));
+
+        let later = now + Duration::from_millis(500);
+        assert_eq!(bucket.try_take(later), Err(Duration::from_millis(500)));
+
+        // Never refills above the burst size
+        let much_later = now + Duration::from_secs(60);
+        assert!(bucket.try_take(much_later).is_ok());
+        assert!(bucket.try_take(much_later).is_ok());
+        assert!(bucket.try_take(much_later).is_err());
+
+        bucket.consume(3.0, much_later);
+        assert_eq!(
+            bucket.time_until_available(much_later),
+            Duration::from_secs(3)
+        );
+        let repaid = much_later + Duration::from_secs(3);
+        assert_eq!(bucket.time_until_available(repaid), Duration::ZERO);
+    }
+}